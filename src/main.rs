@@ -10,7 +10,9 @@ use tcod::console::*;
 use tcod::input::Key;
 use tcod::input::KeyCode::*;
 use tcod::input::{self, Event, Mouse};
+use tcod::line::Line;
 use tcod::map::{FovAlgorithm, Map as FovMap};
+use tcod::pathfinding::AStar;
 use PlayerAction::*;
 
 const BAR_WIDTH: i32 = 20;
@@ -21,18 +23,69 @@ const MSG_X: i32 = BAR_WIDTH + 2;
 const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
 
-const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;
 const FOV_LIGHT_WALLS: bool = true;
-const TORCH_RADIUS: i32 = 10;
+const PLAYER_SIGHT_RADIUS: i32 = 10;
+
+const CONFIG_FILE: &str = "roguelike.cfg";
 
 const FIREBALL_RADIUS: i32 = 3;
 const FIREBALL_DAMAGE: i32 = 12;
+/// frames a lightning bolt flickers for
+const BOLT_FRAMES: i32 = 6;
 
 const CONFUSE_RANGE: i32 = 8;
 const CONFUSE_NUM_TURNS: i32 = 10;
 const LIGHTNING_RANGE: i32 = 5;
 const LIGHTNING_DAMAGE: i32 = 20;
 const INVENTORY_WIDTH: i32 = 50;
+/// one option for every letter of the alphabet
+const MENU_PAGE_SIZE: usize = 26;
+const BASE_CARRY_CAPACITY: i32 = 10;
+const CARRY_CAPACITY_PER_POWER: i32 = 4;
+/// how far past their capacity the player can load themselves, as a percentage
+const MAX_LOAD_PERCENT: i32 = 150;
+/// chance for each room to have some gold lying around
+const GOLD_CHANCE: f32 = 0.3;
+/// gold in a pile, multiplied by the dungeon level
+const GOLD_PER_LEVEL: i32 = 10;
+/// chance for each level below the first to have a merchant
+const MERCHANT_CHANCE: f32 = 0.4;
+const MERCHANT_MIN_WARES: i32 = 3;
+const MERCHANT_MAX_WARES: i32 = 6;
+const MERCHANT_GOLD: i32 = 100;
+const PLAYER_GOLD: i32 = 20;
+/// merchants buy items back for this percentage of their price
+const SELL_PERCENT: i32 = 50;
+/// turns of food the player's stomach can hold
+const FOOD_MAX: i32 = 1500;
+const PLAYER_FOOD: i32 = 1000;
+/// below this much food the player is hungry and stops regenerating
+const HUNGRY_FOOD: i32 = 300;
+/// below this much food the player is too weak to hit as hard
+const WEAK_FOOD: i32 = 100;
+const WEAK_POWER_PENALTY: i32 = 1;
+/// damage every turn once the player's stomach is empty
+const STARVATION_DAMAGE: i32 = 1;
+const RATION_FOOD: i32 = 800;
+const CORPSE_FOOD: i32 = 300;
+/// a corpse gets likelier to make the player sick instead of feeding them the
+/// longer it lies around, until after this many turns it always does
+const CORPSE_ROT_TURNS: i32 = 500;
+const CORPSE_SICK_DAMAGE: i32 = 4;
+/// how unidentified potions can look, shuffled every game
+const POTION_LOOKS: &[&str] = &[
+    "murky", "fizzy", "bubbling", "cloudy", "smoky", "glowing", "oily", "milky",
+];
+/// put together in twos to label unidentified scrolls
+const SCROLL_SYLLABLES: &[&str] = &[
+    "ZEL", "GO", "MER", "FOO", "BIE", "XYZ", "ZY", "KIR", "JE", "VE", "NR", "ELAM", "EBOW", "VAS",
+    "CORP", "DAIYEN", "FOOELS", "ANDOVA", "THARR", "YUM",
+];
+/// every character of the dungeon overview stands for a square this many tiles wide
+const OVERVIEW_SCALE: i32 = 2;
+const LOOK_WIDTH: i32 = 30;
+/// how many frames damage numbers stay on screen
+const FLOATER_FRAMES: i32 = 15;
 const HEAL_AMOUNT: i32 = 4;
 const PLAYER: usize = 0;
 const MAX_ROOM_MONSTERS: i32 = 3;
@@ -40,11 +93,63 @@ const MAX_ROOM_ITEMS: i32 = 2;
 const SCREEN_WIDTH: i32 = 80;
 const SCREEN_HEIGHT: i32 = 50;
 const LIMIT_FPS: i32 = 20;
-const MAP_WIDTH: i32 = 80;
-const MAP_HEIGHT: i32 = 43;
+const MAP_WIDTH: i32 = 120;
+const MAP_HEIGHT: i32 = 70;
+/// the part of the map shown on screen, above the panel. The side panel
+/// takes some of the width, see `Tcod::camera_width`.
+const CAMERA_HEIGHT: i32 = PANEL_Y;
+const SIDEBAR_WIDTH: i32 = 20;
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
-const MAX_ROOMS: i32 = 30;
+const MAX_ROOMS: i32 = 60;
+/// how many times the BSP generator splits the map, giving up to 2^depth rooms
+const BSP_DEPTH: i32 = 5;
+const DOOR_CHANCE: f32 = 0.6;
+/// chance for each door to be a secret one
+const SECRET_DOOR_CHANCE: f32 = 0.15;
+/// chance for a monster to pull a door shut behind itself
+const MONSTER_CLOSE_DOOR_CHANCE: f32 = 0.5;
+/// chance per turn of searching to find each adjacent hidden thing
+const SEARCH_CHANCE: f32 = 0.3;
+/// chance for each room to get a patch of water, lava, rubble or grass
+const TERRAIN_CHANCE: f32 = 0.3;
+const TERRAIN_PATCH_SIZE: i32 = 12;
+const LAVA_DAMAGE: i32 = 6;
+/// chance for each room to get a trap
+const TRAP_CHANCE: f32 = 0.25;
+/// chance per turn of noticing each hidden trap next to the player
+const PASSIVE_SPOT_CHANCE: f32 = 0.05;
+const DISARM_CHANCE: f32 = 0.6;
+const DART_TRAP_DAMAGE: i32 = 4;
+const PIT_TRAP_DAMAGE: i32 = 3;
+const PIT_TRAP_TURNS: i32 = 2;
+const BEAR_TRAP_TURNS: i32 = 4;
+/// turns monsters spend looking for an alarm before giving up
+const ALARM_TURNS: i32 = 30;
+/// chance for each level below the first to be dark
+const DARK_LEVEL_CHANCE: f32 = 0.3;
+const WALL_TORCH_CHANCE: f32 = 0.4;
+const WALL_TORCH_RADIUS: i32 = 6;
+const FUNGUS_CHANCE: f32 = 0.1;
+const FUNGUS_RADIUS: i32 = 2;
+const LANTERN_RADIUS: i32 = 7;
+const LANTERN_FUEL: i32 = 800;
+/// the player gets warned when their lantern gets this low
+const LANTERN_LOW_FUEL: i32 = 100;
+/// tiles with less light than this can't be seen
+const MIN_VISIBLE_LIGHT: f32 = 0.1;
+/// how bright things remembered out of view are drawn
+const REMEMBERED_BRIGHTNESS: f32 = 0.5;
+const PLAYER_DARK_SIGHT: Light = Light {
+    radius: 1,
+    color: colors::DARK_GREY,
+    lit: true,
+    fuel: None,
+};
+const CAVE_FLOOR_CHANCE: f32 = 0.55;
+const CAVE_SMOOTHING_STEPS: i32 = 5;
+/// caves with less floor than this get thrown away and grown again
+const CAVE_MIN_FLOOR: i32 = MAP_WIDTH * MAP_HEIGHT / 3;
 
 const COLOR_DARK_WALL: Color = Color { r: 0, g: 0, b: 100 };
 const COLOR_LIGHT_WALL: Color = Color {
@@ -63,12 +168,186 @@ const COLOR_LIGHT_GROUND: Color = Color {
     b: 50,
 };
 
+/// A line in a menu: either something to pick, or a heading over a group of
+/// options.
+enum MenuLine {
+    Option(String),
+    Heading(String),
+}
+
+/// How the player closed a menu.
+enum MenuResult {
+    Chose(usize),
+    Pressed(char),
+    Cancelled,
+}
+
 struct Tcod {
+    config: Config,
     root: Root,
     con: Offscreen,
     panel: Offscreen,
+    sidebar: Offscreen,
     fov: FovMap,
+    lightmap: LightMap,
     mouse: Mouse,
+    /// map position of the top left corner of the screen
+    camera: (i32, i32),
+    floaters: Vec<Floater>,
+}
+
+/// A bit of text drawn over the map for a few frames, like the damage
+/// something just took.
+struct Floater {
+    x: i32,
+    y: i32,
+    text: String,
+    color: Color,
+    frames_left: i32,
+}
+
+/// Settings that can be changed in `CONFIG_FILE`, one `name = value` per
+/// line. Lines starting with `#` are comments.
+struct Config {
+    fov_algorithm: FovAlgorithm,
+    /// list the monsters and items in view next to the map
+    side_panel: bool,
+    /// show spells flying and exploding
+    animations: bool,
+    inventory_sort: InventorySort,
+    /// how each level is laid out in turn, `None` picking a generator at
+    /// random; the last one carries on for all deeper levels
+    map_generators: Vec<Option<MapGenerator>>,
+}
+
+/// The order items are listed in the inventory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InventorySort {
+    /// potions, then scrolls, then tools, by name within each
+    Category,
+    Name,
+    /// the order they were picked up in
+    Pickup,
+}
+
+impl InventorySort {
+    pub fn name(self) -> &'static str {
+        match self {
+            InventorySort::Category => "category",
+            InventorySort::Name => "name",
+            InventorySort::Pickup => "pickup",
+        }
+    }
+
+    /// The order to switch to from this one in the inventory.
+    pub fn next(self) -> Self {
+        match self {
+            InventorySort::Category => InventorySort::Name,
+            InventorySort::Name => InventorySort::Pickup,
+            InventorySort::Pickup => InventorySort::Category,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            fov_algorithm: FovAlgorithm::Basic,
+            side_panel: true,
+            animations: true,
+            inventory_sort: InventorySort::Category,
+            map_generators: vec![Some(MapGenerator::Rooms), None],
+        }
+    }
+}
+
+impl Config {
+    /// Read the settings from `path`. Anything missing or invalid keeps its
+    /// default, and a missing file just means all defaults.
+    pub fn load(path: &str) -> Self {
+        let mut config = Config::default();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return config,
+        };
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.find('=') {
+                Some(index) => config.set(line[..index].trim(), line[index + 1..].trim()),
+                None => Err(format!("expected `name = value`, got `{}`", line)),
+            };
+            if let Err(error) = result {
+                eprintln!("{}:{}: {}", path, number + 1, error);
+            }
+        }
+        config
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "fov_algorithm" => self.fov_algorithm = parse_fov_algorithm(value)?,
+            "side_panel" => self.side_panel = parse_bool(value)?,
+            "animations" => self.animations = parse_bool(value)?,
+            "inventory_sort" => {
+                self.inventory_sort = match value {
+                    "category" => InventorySort::Category,
+                    "name" => InventorySort::Name,
+                    "pickup" => InventorySort::Pickup,
+                    _ => return Err(format!("unknown inventory order `{}`", value)),
+                }
+            }
+            "map_generators" => self.map_generators = parse_map_generators(value)?,
+            _ => return Err(format!("unknown setting `{}`", name)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        _ => Err(format!("expected `on` or `off`, got `{}`", value)),
+    }
+}
+
+fn parse_fov_algorithm(name: &str) -> Result<FovAlgorithm, String> {
+    use tcod::map::FovAlgorithm::*;
+    let algorithm = match name {
+        "basic" => Basic,
+        "diamond" => Diamond,
+        "shadow" => Shadow,
+        "permissive0" => Permissive0,
+        "permissive1" => Permissive1,
+        "permissive2" => Permissive2,
+        "permissive3" => Permissive3,
+        "permissive4" => Permissive4,
+        "permissive5" => Permissive5,
+        "permissive6" => Permissive6,
+        "permissive7" => Permissive7,
+        "permissive8" => Permissive8,
+        "restrictive" => Restrictive,
+        _ => return Err(format!("unknown FOV algorithm `{}`", name)),
+    };
+    Ok(algorithm)
+}
+
+/// A comma-separated list of map generators, one for each level.
+fn parse_map_generators(value: &str) -> Result<Vec<Option<MapGenerator>>, String> {
+    value
+        .split(',')
+        .map(|name| match name.trim() {
+            "rooms" => Ok(Some(MapGenerator::Rooms)),
+            "bsp" => Ok(Some(MapGenerator::Bsp)),
+            "caves" => Ok(Some(MapGenerator::Caves)),
+            "random" => Ok(None),
+            name => Err(format!("unknown map generator `{}`", name)),
+        })
+        .collect()
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -85,6 +364,12 @@ struct Fighter {
     hp: i32,
     defense: i32,
     power: i32,
+    /// how far it can see, or 0 if it's blind
+    sight_radius: i32,
+    /// turns it takes to regenerate one hit point, or 0 to never regenerate
+    regen_turns: i32,
+    /// turns spent towards regenerating the next hit point
+    regen_progress: i32,
     on_death: DeathCallback,
 }
 
@@ -113,7 +398,8 @@ fn player_death(messages: &mut Messages, player: &mut Object) {
 }
 
 fn monster_death(messages: &mut Messages, monster: &mut Object) {
-    // transform it into a nasty corpse! it doesn't block, can't be attacked and doesn't move.
+    // transform it into a nasty corpse! it doesn't block, can't be attacked and doesn't move,
+    // but a hungry enough player might eat it.
     message(
         messages,
         format!("{} is dead!", monster.name),
@@ -124,7 +410,10 @@ fn monster_death(messages: &mut Messages, monster: &mut Object) {
     monster.blocks = false;
     monster.fighter = None;
     monster.ai = None;
-    monster.name = format!("remains of {}", monster.name)
+    monster.item = Some(Item::Corpse);
+    monster.age = 0;
+    monster.name = format!("remains of {}", monster.name);
+    monster.description = "It won't be bothering anyone again.".into();
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -134,6 +423,14 @@ enum Ai {
         previous_ai: Box<Ai>,
         num_turns: i32,
     },
+    /// heading for where an alarm went off
+    Alerted {
+        previous_ai: Box<Ai>,
+        target: (i32, i32),
+        num_turns: i32,
+    },
+    /// minds their shop and leaves the player alone
+    Merchant,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -142,6 +439,351 @@ enum Item {
     Lightning,
     Confuse,
     Fireball,
+    Lantern,
+    Identify,
+    Ration,
+    Corpse,
+}
+
+impl Item {
+    /// What using the item does, for the player to read.
+    pub fn description(self) -> String {
+        match self {
+            Item::Heal => format!("Drinking it heals {} hit points.", HEAL_AMOUNT),
+            Item::Lightning => format!(
+                "Strikes the closest enemy within {} tiles for {} damage.",
+                LIGHTNING_RANGE, LIGHTNING_DAMAGE
+            ),
+            Item::Confuse => format!(
+                "Confuses an enemy within {} tiles for {} turns.",
+                CONFUSE_RANGE, CONFUSE_NUM_TURNS
+            ),
+            Item::Fireball => format!(
+                "Burns everything within {} tiles of where it's aimed for {} damage.",
+                FIREBALL_RADIUS, FIREBALL_DAMAGE
+            ),
+            Item::Lantern => format!(
+                "Lights up {} tiles around you while it's lit and has fuel.",
+                LANTERN_RADIUS
+            ),
+            Item::Identify => "Reveals what another item is.".into(),
+            Item::Ration => format!("Eating it keeps you fed for {} turns.", RATION_FOOD),
+            Item::Corpse => format!(
+                "Eating it keeps you fed for {} turns, if it hasn't gone off yet.",
+                CORPSE_FOOD
+            ),
+        }
+    }
+
+    pub fn weight(self) -> i32 {
+        match self {
+            Item::Heal | Item::Ration => 2,
+            Item::Lightning | Item::Confuse | Item::Fireball | Item::Identify => 1,
+            Item::Lantern => 5,
+            Item::Corpse => 5,
+        }
+    }
+
+    /// What merchants ask for the item, in gold.
+    pub fn price(self) -> i32 {
+        match self {
+            Item::Heal => 20,
+            Item::Lightning => 40,
+            Item::Confuse => 30,
+            Item::Fireball => 50,
+            Item::Lantern => 25,
+            Item::Identify => 20,
+            Item::Ration => 10,
+            // nobody wants to buy that
+            Item::Corpse => 0,
+        }
+    }
+
+    /// What the item is called once the player knows what it is.
+    pub fn name(self) -> &'static str {
+        match self {
+            Item::Heal => "Healing Potion",
+            Item::Lightning => "Scroll of lightning bolt",
+            Item::Confuse => "scroll of confusion",
+            Item::Fireball => "scroll of fireball",
+            Item::Lantern => "lantern",
+            Item::Identify => "scroll of identify",
+            Item::Ration => "food ration",
+            Item::Corpse => "corpse",
+        }
+    }
+
+    pub fn category(self) -> ItemCategory {
+        match self {
+            Item::Heal => ItemCategory::Potion,
+            Item::Lightning | Item::Confuse | Item::Fireball | Item::Identify => {
+                ItemCategory::Scroll
+            }
+            Item::Ration | Item::Corpse => ItemCategory::Food,
+            Item::Lantern => ItemCategory::Tool,
+        }
+    }
+
+    /// How far around the target tile the item's effect reaches, for items
+    /// that hit an area.
+    pub fn blast_radius(self) -> Option<i32> {
+        match self {
+            Item::Fireball => Some(FIREBALL_RADIUS),
+            _ => None,
+        }
+    }
+}
+
+/// What the player has found out about the items in this game. Potions and
+/// scrolls look different every game, and their real names only show up
+/// once the player has used or identified one.
+struct Discoveries {
+    /// what the items of each kind look like until they are identified
+    appearances: Vec<(Item, String)>,
+    identified: Vec<Item>,
+}
+
+impl Discoveries {
+    /// Come up with new, random looks for all the potions and scrolls.
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        let mut looks = POTION_LOOKS.to_vec();
+        rng.shuffle(&mut looks);
+        let mut appearances = vec![(Item::Heal, format!("{} potion", looks[0]))];
+
+        for &item in &[
+            Item::Lightning,
+            Item::Confuse,
+            Item::Fireball,
+            Item::Identify,
+        ] {
+            // a couple of random syllables, different for every scroll
+            loop {
+                let label = format!(
+                    "{}{}",
+                    SCROLL_SYLLABLES[rng.gen_range(0, SCROLL_SYLLABLES.len())],
+                    SCROLL_SYLLABLES[rng.gen_range(0, SCROLL_SYLLABLES.len())]
+                );
+                let name = format!("scroll labeled {}", label);
+                if appearances.iter().all(|(_, other)| *other != name) {
+                    appearances.push((item, name));
+                    break;
+                }
+            }
+        }
+
+        Discoveries {
+            appearances,
+            identified: vec![],
+        }
+    }
+
+    pub fn is_identified(&self, item: Item) -> bool {
+        self.identified.contains(&item) || self.appearance(item).is_none()
+    }
+
+    fn appearance(&self, item: Item) -> Option<&str> {
+        self.appearances
+            .iter()
+            .find(|(kind, _)| *kind == item)
+            .map(|(_, name)| name.as_str())
+    }
+
+    /// What the player calls items of this kind.
+    pub fn name(&self, item: Item) -> String {
+        match self.appearance(item) {
+            Some(appearance) if !self.is_identified(item) => appearance.into(),
+            _ => item.name().into(),
+        }
+    }
+
+    /// Learn what items of this kind are, renaming all of them the player
+    /// has come across.
+    pub fn identify(
+        &mut self,
+        item: Item,
+        objects: &mut [Object],
+        inventory: &mut [Object],
+        messages: &mut Messages,
+    ) {
+        if self.is_identified(item) {
+            return;
+        }
+        message(
+            messages,
+            format!("The {} was a {}!", self.name(item), item.name()),
+            colors::LIGHT_CYAN,
+        );
+        self.identified.push(item);
+        for object in objects.iter_mut().chain(inventory.iter_mut()) {
+            if object.item == Some(item) {
+                object.name = item.name().into();
+            }
+            // what merchants sell as well, or it wouldn't stack with the
+            // player's once bought
+            for ware in &mut object.wares {
+                if ware.item == Some(item) {
+                    ware.name = item.name().into();
+                }
+            }
+        }
+    }
+}
+
+/// What kind of thing an item is, to group it with similar ones in the
+/// inventory. The inventory lists them in this order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum ItemCategory {
+    Potion,
+    Scroll,
+    Food,
+    Tool,
+}
+
+impl ItemCategory {
+    /// The title of this category's section in the inventory.
+    pub fn heading(self) -> &'static str {
+        match self {
+            ItemCategory::Potion => "Potions",
+            ItemCategory::Scroll => "Scrolls",
+            ItemCategory::Food => "Food",
+            ItemCategory::Tool => "Tools",
+        }
+    }
+}
+
+/// Something a monster can leave behind when it dies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Loot {
+    Nothing,
+    /// a pile of gold, bigger on deeper levels
+    Gold,
+    Item(Item),
+}
+
+/// One entry of a loot table: what can drop, and how likely it is compared
+/// to the other entries.
+#[derive(Debug)]
+struct LootDrop {
+    loot: Loot,
+    weight: u32,
+    /// added to the weight for every level below the first, so better loot
+    /// gets more common deeper down
+    weight_per_level: u32,
+}
+
+const ORC_LOOT: &[LootDrop] = &[
+    LootDrop {
+        loot: Loot::Nothing,
+        weight: 60,
+        weight_per_level: 0,
+    },
+    LootDrop {
+        loot: Loot::Gold,
+        weight: 25,
+        weight_per_level: 2,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Heal),
+        weight: 10,
+        weight_per_level: 2,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Ration),
+        weight: 5,
+        weight_per_level: 0,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Confuse),
+        weight: 3,
+        weight_per_level: 1,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Lightning),
+        weight: 2,
+        weight_per_level: 1,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Fireball),
+        weight: 0,
+        weight_per_level: 1,
+    },
+];
+
+const TROLL_LOOT: &[LootDrop] = &[
+    LootDrop {
+        loot: Loot::Nothing,
+        weight: 30,
+        weight_per_level: 0,
+    },
+    LootDrop {
+        loot: Loot::Gold,
+        weight: 35,
+        weight_per_level: 3,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Heal),
+        weight: 15,
+        weight_per_level: 2,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Lightning),
+        weight: 5,
+        weight_per_level: 2,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Fireball),
+        weight: 5,
+        weight_per_level: 2,
+    },
+    LootDrop {
+        loot: Loot::Item(Item::Lantern),
+        weight: 5,
+        weight_per_level: 0,
+    },
+];
+
+/// Something that gives off light: a torch, some fungus, a lantern...
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Light {
+    radius: i32,
+    color: Color,
+    lit: bool,
+    /// turns left before it burns out, if it ever does
+    fuel: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Trap {
+    kind: TrapKind,
+    hidden: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TrapKind {
+    Dart,
+    Pit,
+    Teleport,
+    Alarm,
+    BearTrap,
+}
+
+impl TrapKind {
+    /// What the trap does to whoever steps on it.
+    pub fn description(self) -> String {
+        match self {
+            TrapKind::Dart => format!("Shoots a dart for {} damage.", DART_TRAP_DAMAGE),
+            TrapKind::Pit => format!(
+                "A hole to fall into for {} damage, taking {} turns to climb out.",
+                PIT_TRAP_DAMAGE, PIT_TRAP_TURNS
+            ),
+            TrapKind::Teleport => "Sends whoever steps on it somewhere else on the level.".into(),
+            TrapKind::Alarm => "Rings loudly, calling every monster on the level.".into(),
+            TrapKind::BearTrap => {
+                format!("Holds whoever steps on it for {} turns.", BEAR_TRAP_TURNS)
+            }
+        }
+    }
 }
 
 enum UseResult {
@@ -149,7 +791,44 @@ enum UseResult {
     Cancelled,
 }
 
-#[derive(Debug)]
+/// A landmark the player can travel to once they know where it is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Feature {
+    Stairs,
+    Shop,
+}
+
+/// How the player's stomach is doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hunger {
+    Fed,
+    Hungry,
+    Weak,
+    Starving,
+}
+
+impl Hunger {
+    /// What the panel shows, nothing while the player is fed.
+    pub fn status(self) -> &'static str {
+        match self {
+            Hunger::Fed => "",
+            Hunger::Hungry => "Hungry",
+            Hunger::Weak => "Weak",
+            Hunger::Starving => "Starving",
+        }
+    }
+
+    pub fn color(self) -> Color {
+        match self {
+            Hunger::Fed => colors::WHITE,
+            Hunger::Hungry => colors::YELLOW,
+            Hunger::Weak => colors::ORANGE,
+            Hunger::Starving => colors::RED,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 struct Object {
     x: i32,
     y: i32,
@@ -158,9 +837,35 @@ struct Object {
     name: String,
     blocks: bool,
     alive: bool,
+    always_visible: bool,
+    /// turns left caught in a trap, unable to move
+    stuck: i32,
+    /// turns a monster still spends crossing slow ground before it can act
+    move_delay: i32,
     fighter: Option<Fighter>,
     ai: Option<Ai>,
     item: Option<Item>,
+    trap: Option<Trap>,
+    light: Option<Light>,
+    feature: Option<Feature>,
+    /// where the player last saw this object, if they have seen it at all
+    last_seen: Option<(i32, i32)>,
+    /// shown when the player looks at it
+    description: String,
+    /// damage taken since it was last shown on screen
+    recent_damage: i32,
+    /// how many identical items this object stands for
+    count: i32,
+    /// gold carried, or lying in this pile
+    gold: i32,
+    /// what a merchant has for sale
+    wares: Vec<Object>,
+    /// what it might drop when it dies
+    loot: &'static [LootDrop],
+    /// turns of food left in its stomach, for things that need to eat
+    food: Option<i32>,
+    /// turns since it died, for corpses
+    age: i32,
 }
 
 impl Object {
@@ -173,9 +878,24 @@ impl Object {
             color,
             blocks,
             alive: false,
+            always_visible: false,
+            stuck: 0,
+            move_delay: 0,
             fighter: None,
             ai: None,
             item: None,
+            trap: None,
+            light: None,
+            feature: None,
+            last_seen: None,
+            description: String::new(),
+            recent_damage: 0,
+            count: 1,
+            gold: 0,
+            wares: vec![],
+            loot: &[],
+            food: None,
+            age: 0,
         }
     }
 
@@ -190,6 +910,47 @@ impl Object {
         con.put_char(self.x, self.y, ' ', BackgroundFlag::None);
     }
 
+    /// Whether this is a trap the player hasn't found yet.
+    pub fn is_hidden(&self) -> bool {
+        self.trap.map(|trap| trap.hidden) == Some(true)
+    }
+
+    /// Whether it's a monster that's out to get the player.
+    pub fn is_hostile(&self) -> bool {
+        self.ai.is_some() && self.ai != Some(Ai::Merchant)
+    }
+
+    /// How heavy all the items this object stands for are together.
+    pub fn weight(&self) -> i32 {
+        self.item.map_or(0, |item| item.weight() * self.count)
+    }
+
+    /// The name, with how many there are if there's more than one.
+    pub fn display_name(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.name, self.count)
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// Whether the two objects are the same kind of item and can share an
+    /// inventory slot. Light sources each burn their own fuel and corpses each
+    /// rot at their own pace, so they don't.
+    pub fn stacks_with(&self, other: &Object) -> bool {
+        self.item.is_some()
+            && self.item == other.item
+            && self.name == other.name
+            && self.light.is_none()
+            && other.light.is_none()
+            && self.item != Some(Item::Corpse)
+    }
+
+    /// The chance that eating this corpse makes the player sick.
+    pub fn rot_chance(&self) -> f32 {
+        (self.age as f32 / CORPSE_ROT_TURNS as f32).min(1.0)
+    }
+
     pub fn pos(&self) -> (i32, i32) {
         (self.x, self.y)
     }
@@ -213,6 +974,7 @@ impl Object {
         if let Some(fighter) = self.fighter.as_mut() {
             if damage > 0 {
                 fighter.hp -= damage;
+                self.recent_damage += damage;
             }
         }
         if let Some(fighter) = self.fighter {
@@ -232,9 +994,60 @@ impl Object {
         }
     }
 
+    /// How hungry it is, if it needs to eat at all.
+    pub fn hunger(&self) -> Option<Hunger> {
+        self.food.map(|food| {
+            if food <= 0 {
+                Hunger::Starving
+            } else if food < WEAK_FOOD {
+                Hunger::Weak
+            } else if food < HUNGRY_FOOD {
+                Hunger::Hungry
+            } else {
+                Hunger::Fed
+            }
+        })
+    }
+
+    /// Whether it's gotten hungry enough that it won't heal or rest.
+    pub fn is_hungry(&self) -> bool {
+        self.food.is_some() && self.hunger() != Some(Hunger::Fed)
+    }
+
+    /// Pass one turn of natural regeneration.
+    pub fn regenerate(&mut self) {
+        // the dead stay dead
+        if !self.alive {
+            return;
+        }
+        // wounds don't heal on an empty stomach
+        if self.is_hungry() {
+            return;
+        }
+        let healed = match self.fighter.as_mut() {
+            Some(fighter) if fighter.regen_turns > 0 && fighter.hp < fighter.max_hp => {
+                fighter.regen_progress += 1;
+                if fighter.regen_progress >= fighter.regen_turns {
+                    fighter.regen_progress = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => false,
+        };
+        if healed {
+            self.heal(1);
+        }
+    }
+
     pub fn attack(&mut self, messages: &mut Messages, target: &mut Object) {
         // a simple formula for attack damage
-        let damage = self.fighter.map_or(0, |f| f.power) - target.fighter.map_or(0, |f| f.defense);
+        let mut damage =
+            self.fighter.map_or(0, |f| f.power) - target.fighter.map_or(0, |f| f.defense);
+        if let Some(Hunger::Weak) | Some(Hunger::Starving) = self.hunger() {
+            damage -= WEAK_POWER_PENALTY;
+        }
         if damage > 0 {
             // make the target take some damage
             message(
@@ -259,6 +1072,23 @@ impl Object {
     }
 }
 
+/// Whether `viewer` can see the tile at (x, y): it has to be within its sight
+/// radius with nothing blocking the line in between. Anything right next to
+/// a creature can always be felt, even when it's blind.
+fn can_see(viewer: &Object, x: i32, y: i32, map: &Map) -> bool {
+    let distance = viewer.distance(x, y);
+    if distance < 2.0 {
+        return true;
+    }
+    let sight_radius = viewer.fighter.map_or(0, |f| f.sight_radius);
+    if distance > sight_radius as f32 {
+        return false;
+    }
+    Line::new(viewer.pos(), (x, y))
+        .take_while(|&pos| pos != (x, y))
+        .all(|(line_x, line_y)| !map[line_x as usize][line_y as usize].block_sight)
+}
+
 fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
     if map[x as usize][y as usize].blocked {
         return true;
@@ -269,596 +1099,3061 @@ fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
         .any(|object| object.blocks && object.pos() == (x, y))
 }
 
-fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+fn move_by(
+    id: usize,
+    dx: i32,
+    dy: i32,
+    map: &mut Map,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    tcod: &Tcod,
+) {
     let (x, y) = objects[id].pos();
-    if !is_blocked(x + dx, y + dy, map, objects) {
+    if objects[id].stuck > 0 {
+        objects[id].stuck -= 1;
+        if id == PLAYER {
+            message(messages, "You are stuck in the trap!", colors::RED);
+        }
+    } else if map[(x + dx) as usize][(y + dy) as usize].door == Some(Door::Closed) {
+        // bumping into a closed door opens it, which takes the whole move
+        set_door(x + dx, y + dy, Door::Open, map);
+    } else if !is_blocked(x + dx, y + dy, map, objects) {
         objects[id].set_pos(x + dx, y + dy);
-    }
-}
-
-fn move_towards(id: usize, target_x: i32, target_y: i32, map: &Map, objects: &mut [Object]) {
-    // vector from this object to the target, and distance
-    let dx = target_x - objects[id].x;
-    let dy = target_y - objects[id].y;
-    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
 
-    // normalize it to length 1 (preserving direction), then round it and
-    // convert to integer so the movement is restricted to the map grid
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
-    move_by(id, dx, dy, map, objects);
-}
+        // monsters sometimes close the doors they walk through
+        let left_door = map[x as usize][y as usize].door == Some(Door::Open);
+        if id != PLAYER
+            && left_door
+            && !objects.iter().any(|object| object.pos() == (x, y))
+            && rand::random::<f32>() < MONSTER_CLOSE_DOOR_CHANCE
+        {
+            set_door(x, y, Door::Closed, map);
+        }
 
-fn pick_item_up(
-    object_id: usize,
-    objects: &mut Vec<Object>,
-    inventory: &mut Vec<Object>,
-    messages: &mut Messages,
-) {
-    if inventory.len() >= 26 {
-        message(
-            messages,
-            format!(
-                "Your inventory is full, cannot pick up {}.",
-                objects[object_id].name
-            ),
-            colors::RED,
-        )
-    } else {
-        let item = objects.swap_remove(object_id);
-        message(
-            messages,
-            format!("You picked up a {}!", item.name),
-            colors::GREEN,
-        );
-        inventory.push(item)
+        let trap_id = objects
+            .iter()
+            .position(|object| object.trap.is_some() && object.pos() == (x + dx, y + dy));
+        if let Some(trap_id) = trap_id {
+            trigger_trap(trap_id, id, map, objects, messages, tcod);
+        }
     }
 }
 
-fn player_move_or_attack(
-    dx: i32,
-    dy: i32,
+/// Spring the trap `trap_id` on whoever stepped onto it.
+fn trigger_trap(
+    trap_id: usize,
+    victim_id: usize,
     map: &Map,
-    messages: &mut Messages,
     objects: &mut [Object],
+    messages: &mut Messages,
+    tcod: &Tcod,
 ) {
-    // the coordinates the player is moving to/attacking
-    let x = objects[PLAYER].x + dx;
-    let y = objects[PLAYER].y + dy;
-
-    // try to find an attackable object there
-    let target_id = objects
-        .iter()
-        .position(|object| object.fighter.is_some() && object.pos() == (x, y));
-
-    match target_id {
-        Some(target_id) => {
-            let (player, target) = mut_two(PLAYER, target_id, objects);
-            player.attack(messages, target);
+    let (trap_x, trap_y) = objects[trap_id].pos();
+    // traps going off out of sight stay hidden, and the player doesn't hear about them
+    let seen = victim_id == PLAYER || tcod.is_visible(trap_x, trap_y);
+    let trap = match objects[trap_id].trap.as_mut() {
+        Some(trap) => {
+            if seen {
+                trap.hidden = false;
+            }
+            *trap
+        }
+        None => return,
+    };
+    let name = objects[victim_id].name.clone();
+
+    match trap.kind {
+        TrapKind::Dart => {
+            if seen {
+                message(
+                    messages,
+                    format!("A dart shoots out at {}!", name),
+                    colors::ORANGE,
+                );
+            }
+            objects[victim_id].take_damage(messages, DART_TRAP_DAMAGE);
+        }
+        TrapKind::Pit => {
+            if seen {
+                message(
+                    messages,
+                    format!("{} falls into a pit!", name),
+                    colors::ORANGE,
+                );
+            }
+            objects[victim_id].stuck = PIT_TRAP_TURNS;
+            objects[victim_id].take_damage(messages, PIT_TRAP_DAMAGE);
+        }
+        TrapKind::BearTrap => {
+            if seen {
+                message(
+                    messages,
+                    format!("A bear trap snaps shut on {}!", name),
+                    colors::ORANGE,
+                );
+            }
+            objects[victim_id].stuck = BEAR_TRAP_TURNS;
+        }
+        TrapKind::Teleport => {
+            if seen {
+                message(
+                    messages,
+                    format!("{} vanishes in a flash of light!", name),
+                    colors::LIGHT_VIOLET,
+                );
+            }
+            loop {
+                let x = rand::thread_rng().gen_range(0, MAP_WIDTH);
+                let y = rand::thread_rng().gen_range(0, MAP_HEIGHT);
+                // somewhere safe to land: no lava, deep water or another trap
+                let safe = !map[x as usize][y as usize].kind.is_hazard()
+                    && !objects
+                        .iter()
+                        .any(|o| o.trap.is_some() && o.pos() == (x, y));
+                if safe && !is_blocked(x, y, map, objects) {
+                    objects[victim_id].set_pos(x, y);
+                    break;
+                }
+            }
+        }
+        TrapKind::Alarm => {
+            // loud enough to hear from anywhere, but not where it came from
+            if seen {
+                message(messages, "A loud alarm rings out!", colors::RED);
+            } else {
+                message(messages, "You hear an alarm in the distance!", colors::RED);
+            }
+            for object in objects.iter_mut().filter(|o| o.is_hostile()) {
+                if let Some(ai) = object.ai.take() {
+                    // a second alarm just changes where they are headed
+                    let previous_ai = match ai {
+                        Ai::Alerted { previous_ai, .. } => previous_ai,
+                        ai => Box::new(ai),
+                    };
+                    object.ai = Some(Ai::Alerted {
+                        previous_ai,
+                        target: (trap_x, trap_y),
+                        num_turns: ALARM_TURNS,
+                    });
+                }
+            }
         }
-        None => move_by(PLAYER, dx, dy, map, objects),
     }
 }
 
-fn cast_fireball(
-    _inventory_id: usize,
+/// Apply the effects of the tile that object `id` just walked onto.
+fn enter_tile(
+    id: usize,
+    map: &Map,
     objects: &mut [Object],
+    inventory: &mut Vec<Object>,
     messages: &mut Messages,
-    map: &mut Map,
-    tcod: &mut Tcod,
-) -> UseResult {
-    // ask the player for a target tile to throw a fireball at
-    message(
-        messages,
-        "Left-click a target tile for the fireball, or right-click to cancel.",
-        colors::LIGHT_CYAN,
-    );
-    let (x, y) = match tcod.target_tile(objects, map, messages, None) {
-        Some(tile_pos) => tile_pos,
-        None => return UseResult::Cancelled,
-    };
-    message(
-        messages,
-        format!(
-            "The fireball explodes, burning everything within {} tiles!",
-            FIREBALL_RADIUS
-        ),
-        colors::ORANGE,
-    );
-
-    for obj in objects {
-        if obj.distance(x, y) <= FIREBALL_RADIUS as f32 && obj.fighter.is_some() {
+) {
+    let (x, y) = objects[id].pos();
+    let kind = map[x as usize][y as usize].kind;
+    if id != PLAYER {
+        objects[id].move_delay = kind.move_cost() - 1;
+    }
+    match kind {
+        TileKind::Lava if objects[id].fighter.is_some() => {
             message(
                 messages,
-                format!(
-                    "The {} gets burned for {} hit points.",
-                    obj.name, FIREBALL_DAMAGE
-                ),
-                colors::ORANGE,
+                format!("The lava burns {}!", objects[id].name),
+                colors::FLAME,
+            );
+            objects[id].take_damage(messages, LAVA_DAMAGE);
+        }
+        TileKind::DeepWater if id == PLAYER && !inventory.is_empty() => {
+            let lost = take_one(inventory, rand::thread_rng().gen_range(0, inventory.len()));
+            message(
+                messages,
+                format!("Your {} is swept away by the deep water!", lost.name),
+                colors::LIGHT_BLUE,
             );
-            obj.take_damage(messages, FIREBALL_DAMAGE);
         }
+        _ => {}
     }
-
-    UseResult::UsedUp
 }
 
-fn cast_confuse(
-    _inventory_id: usize,
+fn move_towards(
+    id: usize,
+    target_x: i32,
+    target_y: i32,
+    map: &mut Map,
     objects: &mut [Object],
     messages: &mut Messages,
-    map: &mut Map,
-    tcod: &mut Tcod,
-) -> UseResult {
-    message(
-        messages,
-        "Left-click an enemy to confuse it, or right-click to cancel.",
-        colors::LIGHT_CYAN,
-    );
-    let monster_id = tcod.target_monster(objects, map, messages, Some(CONFUSE_RANGE as f32));
-    if let Some(monster_id) = monster_id {
-        let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
-        // replace the monster's AI with a "confused" one; after
-        // some turns it will restore the old AI
-        objects[monster_id].ai = Some(Ai::Confused {
-            previous_ai: Box::new(old_ai),
-            num_turns: CONFUSE_NUM_TURNS,
-        });
+    tcod: &Tcod,
+) {
+    // vector from this object to the target, and distance
+    let dx = target_x - objects[id].x;
+    let dy = target_y - objects[id].y;
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+
+    // normalize it to length 1 (preserving direction), then round it and
+    // convert to integer so the movement is restricted to the map grid
+    let dx = (dx as f32 / distance).round() as i32;
+    let dy = (dy as f32 / distance).round() as i32;
+
+    // step around lava and deep water rather than into it, or wait if there
+    // is no other way
+    let (x, y) = objects[id].pos();
+    let step = [(dx, dy), (dx, 0), (0, dy)]
+        .iter()
+        .find(|&&(dx, dy)| {
+            (dx, dy) != (0, 0) && !map[(x + dx) as usize][(y + dy) as usize].kind.is_hazard()
+        })
+        .cloned();
+    if let Some((dx, dy)) = step {
+        move_by(id, dx, dy, map, objects, messages, tcod);
+    }
+}
+
+fn pick_item_up(
+    object_id: usize,
+    objects: &mut Vec<Object>,
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+) {
+    // gold goes in the purse, not the pack
+    if objects[object_id].item.is_none() {
+        let pile = objects.swap_remove(object_id);
         message(
             messages,
-            format!(
-                "The eyes of {} look vacant, as he starts to stumble around!",
-                objects[monster_id].name
-            ),
-            colors::LIGHT_GREEN,
+            format!("You picked up {} gold pieces.", pile.gold),
+            colors::GOLD,
         );
-        UseResult::UsedUp
-    } else {
-        // no enemy fonud within maximum range
-        message(messages, "No enemy is close enough to strike.", colors::RED);
-        UseResult::Cancelled
+        objects[PLAYER].gold += pile.gold;
+        return;
     }
-}
 
-fn cast_lightning(
-    _inventory_id: usize,
-    objects: &mut [Object],
-    messages: &mut Messages,
-    map: &mut Map,
-    tcod: &mut Tcod,
-) -> UseResult {
-    // find closest enemy (inside a maximum range) and damage it
-    let monster_id = closest_monster(LIGHTNING_RANGE, objects, tcod);
-    if let Some(monster_id) = monster_id {
-        // zap it!
+    let capacity = carry_capacity(&objects[PLAYER]);
+    let weight_before = inventory_weight(inventory);
+    let weight_after = weight_before + objects[object_id].weight();
+    if weight_after > capacity * MAX_LOAD_PERCENT / 100 {
         message(
             messages,
             format!(
-                "A lightning bolt strikes the {} with a loud thunder! \
-                 The damage is {} hit points.",
-                objects[monster_id].name, LIGHTNING_DAMAGE
+                "You can't carry the {} as well, your pack is too heavy.",
+                objects[object_id].name
             ),
-            colors::LIGHT_BLUE,
+            colors::RED,
         );
-        objects[monster_id].take_damage(messages, LIGHTNING_DAMAGE);
-        UseResult::UsedUp
+        return;
+    }
+
+    let item = objects.swap_remove(object_id);
+    message(
+        messages,
+        format!("You picked up a {}!", item.name),
+        colors::GREEN,
+    );
+    add_item(inventory, item);
+    if weight_before <= capacity && weight_after > capacity {
+        message(
+            messages,
+            "You are burdened by the weight of your pack, and move slowly.",
+            colors::LIGHT_YELLOW,
+        );
+    }
+}
+
+/// How much weight the player can carry before it slows them down.
+fn carry_capacity(player: &Object) -> i32 {
+    player.fighter.map_or(0, |f| {
+        BASE_CARRY_CAPACITY + f.power * CARRY_CAPACITY_PER_POWER
+    })
+}
+
+fn inventory_weight(inventory: &[Object]) -> i32 {
+    inventory.iter().map(|item| item.weight()).sum()
+}
+
+/// Put the item in with the others, on a stack of the same items if there is one.
+fn add_item(items: &mut Vec<Object>, item: Object) {
+    match items.iter().position(|other| other.stacks_with(&item)) {
+        Some(stack) => items[stack].count += item.count,
+        None => items.push(item),
+    }
+}
+
+/// Take a single item off the inventory slot, removing the slot if it was
+/// the last one.
+fn take_one(inventory: &mut Vec<Object>, inventory_id: usize) -> Object {
+    if inventory[inventory_id].count > 1 {
+        inventory[inventory_id].count -= 1;
+        let mut item = inventory[inventory_id].clone();
+        item.count = 1;
+        item
     } else {
-        // no enemy found within maximum range
-        message(messages, "No enemy is close enough to strike.", colors::RED);
-        UseResult::Cancelled
+        inventory.remove(inventory_id)
     }
 }
 
-fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &Tcod) -> Option<usize> {
-    let mut closest_enemy = None;
-    let mut closest_dist = (max_range + 1) as f32;
+/// Returns whether the player moved or attacked, rather than just bumping
+/// into a merchant.
+fn player_move_or_attack(
+    dx: i32,
+    dy: i32,
+    map: &mut Map,
+    messages: &mut Messages,
+    objects: &mut [Object],
+    tcod: &Tcod,
+) -> bool {
+    // the coordinates the player is moving to/attacking
+    let x = objects[PLAYER].x + dx;
+    let y = objects[PLAYER].y + dy;
 
-    for (id, object) in objects.iter().enumerate() {
-        if (id != PLAYER)
-            && object.fighter.is_some()
-            && object.ai.is_some()
-            && tcod.fov.is_in_fov(object.x, object.y)
-        {
-            let dist = objects[PLAYER].distance_to(object);
-            if dist < closest_dist {
-                closest_enemy = Some(id);
-                closest_dist = dist;
+    // try to find an attackable object there
+    let target_id = objects
+        .iter()
+        .position(|object| object.fighter.is_some() && object.pos() == (x, y));
+
+    let merchant = objects
+        .iter()
+        .find(|object| object.ai == Some(Ai::Merchant) && object.pos() == (x, y));
+    match (target_id, merchant) {
+        (Some(target_id), _) => {
+            let (player, target) = mut_two(PLAYER, target_id, objects);
+            player.attack(messages, target);
+        }
+        (None, Some(merchant)) => {
+            message(
+                messages,
+                format!(
+                    "The {} would rather trade with you. Press 't'.",
+                    merchant.name
+                ),
+                colors::GOLD,
+            );
+            return false;
+        }
+        (None, None) => move_by(PLAYER, dx, dy, map, objects, messages, tcod),
+    }
+    true
+}
+
+/// Close an open door next to the player. Returns whether there was one.
+fn close_door(map: &mut Map, objects: &[Object], messages: &mut Messages) -> bool {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (x, y) = (player_x + dx, player_y + dy);
+            let occupied = objects.iter().any(|object| object.pos() == (x, y));
+            if map[x as usize][y as usize].door == Some(Door::Open) && !occupied {
+                set_door(x, y, Door::Closed, map);
+                message(messages, "You close the door.", colors::WHITE);
+                return true;
             }
         }
     }
-    closest_enemy
+    message(
+        messages,
+        "There is no open door next to you.",
+        colors::WHITE,
+    );
+    false
 }
 
-fn cast_heal(
-    _inventory_id: usize,
-    objects: &mut [Object],
-    messages: &mut Messages,
-    map: &mut Map,
-    _tcod: &mut Tcod,
-) -> UseResult {
-    if let Some(fighter) = objects[PLAYER].fighter {
-        if fighter.hp == fighter.max_hp {
-            message(messages, "You are already at full health.", colors::RED);
-            return UseResult::Cancelled;
+/// Spend a turn looking for secret doors and traps next to the player.
+fn search(map: &mut Map, objects: &mut [Object], messages: &mut Messages) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            let (x, y) = (player_x + dx, player_y + dy);
+            if map[x as usize][y as usize].door == Some(Door::Secret)
+                && rand::random::<f32>() < SEARCH_CHANCE
+            {
+                set_door(x, y, Door::Closed, map);
+                message(messages, "You find a secret door!", colors::LIGHT_CYAN);
+            }
+        }
+    }
+    spot_traps(objects, messages, SEARCH_CHANCE);
+}
+
+/// Give each hidden trap next to the player `chance` of being noticed.
+fn spot_traps(objects: &mut [Object], messages: &mut Messages, chance: f32) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    for object in objects.iter_mut() {
+        let nearby = (object.x - player_x).abs() <= 1 && (object.y - player_y).abs() <= 1;
+        if object.is_hidden() && nearby && rand::random::<f32>() < chance {
+            if let Some(trap) = object.trap.as_mut() {
+                trap.hidden = false;
+            }
+            message(
+                messages,
+                format!("You find a {}!", object.name),
+                colors::LIGHT_CYAN,
+            );
+        }
+    }
+}
+
+/// Try to disarm a known trap under or next to the player. Returns whether
+/// there was one to work on.
+fn disarm_trap(map: &Map, objects: &mut Vec<Object>, messages: &mut Messages, tcod: &Tcod) -> bool {
+    let trap_id = objects.iter().position(|object| {
+        object.trap.is_some() && !object.is_hidden() && object.distance_to(&objects[PLAYER]) < 1.5
+    });
+    let trap_id = match trap_id {
+        Some(trap_id) => trap_id,
+        None => {
+            message(
+                messages,
+                "There is no trap you know of nearby.",
+                colors::WHITE,
+            );
+            return false;
         }
+    };
 
+    let dice = rand::random::<f32>();
+    if dice < DISARM_CHANCE {
+        let trap = objects.swap_remove(trap_id);
         message(
             messages,
-            "Your wounds start ot feel better!",
-            colors::LIGHT_VIOLET,
+            format!("You disarm the {}.", trap.name),
+            colors::LIGHT_GREEN,
         );
-        objects[PLAYER].heal(HEAL_AMOUNT);
-        return UseResult::UsedUp;
+    } else if dice < DISARM_CHANCE + (1.0 - DISARM_CHANCE) / 2.0 {
+        message(messages, "You set the trap off!", colors::RED);
+        trigger_trap(trap_id, PLAYER, map, objects, messages, tcod);
+    } else {
+        message(messages, "You fail to disarm the trap.", colors::WHITE);
     }
-    UseResult::Cancelled
+    true
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Tile {
-    blocked: bool,
-    block_sight: bool,
-    explored: bool,
+/// Something the player keeps doing turn after turn, until it's done or gets
+/// interrupted.
+enum Activity {
+    /// walk along a path, given in walking order
+    Travel(Vec<(i32, i32)>),
+    /// pass turns until healed
+    Rest,
 }
 
-impl Tile {
-    pub fn empty() -> Self {
-        Tile {
-            blocked: false,
-            block_sight: false,
-            explored: false,
+impl Activity {
+    fn verb(&self) -> &'static str {
+        match self {
+            Activity::Travel(_) => "travelling",
+            Activity::Rest => "resting",
         }
     }
+}
 
-    pub fn wall() -> Self {
-        Tile {
-            blocked: true,
-            block_sight: true,
-            explored: false,
+/// An activity in progress, with the player's HP and hunger after its last
+/// turn so that taking damage or getting hungrier can interrupt it.
+struct Occupation {
+    activity: Activity,
+    last_hp: i32,
+    last_hunger: Option<Hunger>,
+}
+
+impl Occupation {
+    fn new(activity: Activity, objects: &[Object]) -> Self {
+        Occupation {
+            activity,
+            last_hp: objects[PLAYER].fighter.map_or(0, |f| f.hp),
+            last_hunger: objects[PLAYER].hunger(),
         }
     }
 }
 
-type Messages = Vec<(String, Color)>;
-type Map = Vec<Vec<Tile>>;
-
-fn message<T: Into<String>>(messages: &mut Messages, message: T, color: Color) {
-    if messages.len() == MSG_HEIGHT {
-        messages.remove(0);
+/// Find a path over explored, walkable tiles, around any traps the player
+/// knows of. The returned steps are in walking order and don't include the
+/// starting position.
+fn find_travel_path(
+    from: (i32, i32),
+    to: (i32, i32),
+    map: &Map,
+    objects: &[Object],
+) -> Option<Vec<(i32, i32)>> {
+    let (to_x, to_y) = to;
+    if to_x < 0 || to_y < 0 || to_x >= MAP_WIDTH || to_y >= MAP_HEIGHT || from == to {
+        return None;
     }
 
-    messages.push((message.into(), color))
-}
-
-fn make_map(objects: &mut Vec<Object>) -> Map {
-    // fill map with "unblocked" tiles
-    let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
-    let mut starting_position = (0, 0);
-
-    let mut rooms = vec![];
-
-    for _ in 0..MAX_ROOMS {
-        // random width and height
-        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
-        // random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
-        let new_room = Rect::new(x, y, w, h);
-
-        // run through the other rooms and see if they intersect with this one
-        let failed = rooms
-            .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
-
-        if !failed {
-            // this means there are no intersections, so this room is valid
-
-            // "paint" it to the map's tiles
-            create_room(new_room, &mut map);
-            place_objects(new_room, &map, objects);
-
-            // center coordinates of the new room, will be useful later
-            let (new_x, new_y) = new_room.center();
-
-            if rooms.is_empty() {
-                // this is the first room, where the player starts at
-                starting_position = (new_x, new_y);
+    let known_traps: Vec<(i32, i32)> = objects
+        .iter()
+        .filter(|o| o.trap.is_some() && !o.is_hidden())
+        .map(|o| o.pos())
+        .collect();
+    let mut path = AStar::new_from_callback(
+        MAP_WIDTH,
+        MAP_HEIGHT,
+        |_from, (x, y)| {
+            let tile = &map[x as usize][y as usize];
+            let known_trap = known_traps.contains(&(x, y));
+            let dangerous = tile.kind.is_hazard() || known_trap;
+            // a cost of 0 tells libtcod the tile can't be walked on
+            if tile.explored && tile.is_walkable() && !dangerous {
+                tile.kind.move_cost() as f32
             } else {
-                // all rooms after the first:
-                // connect it to the previous room with a tunnel
-
-                // center coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1].center();
-
-                // draw a coin (random bool value -- either true or false)
-                if rand::random() {
-                    // first move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    // first move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
+                0.0
             }
-            // finally, append the new room to the list
-            rooms.push(new_room);
-        }
-    }
-
-    objects[PLAYER].set_pos(starting_position.0, starting_position.1);
-    map
-}
-
-fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>) {
-    let num_monsters = rand::thread_rng().gen_range(0, MAX_ROOM_MONSTERS + 1);
-    let num_items = rand::thread_rng().gen_range(0, MAX_ROOM_ITEMS + 1);
-
-    for _ in 0..num_monsters {
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
-
-        if !is_blocked(x, y, map, objects) {
-            let mut monster = if rand::random::<f32>() < 0.8 {
-                // create an orc
-                let mut orc = Object::new(x, y, 'o', "Orc", colors::DESATURATED_GREEN, true);
-                orc.fighter = Some(Fighter {
-                    max_hp: 10,
-                    hp: 10,
-                    defense: 0,
-                    power: 3,
-                    on_death: DeathCallback::Monster,
-                });
-                orc.ai = Some(Ai::Basic);
-                orc
-            } else {
-                let mut troll = Object::new(x, y, 'T', "Troll", colors::DARKER_GREEN, true);
-                troll.fighter = Some(Fighter {
-                    max_hp: 16,
-                    hp: 16,
-                    defense: 1,
-                    power: 4,
-                    on_death: DeathCallback::Monster,
-                });
-                troll.ai = Some(Ai::Basic);
-                troll
-            };
-            monster.alive = true;
-
-            objects.push(monster);
-        }
-    }
-
-    for _ in 0..num_items {
-        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
-
-        if !is_blocked(x, y, map, objects) {
-            let dice = rand::random::<f32>();
-
-            let item = if dice < 0.7 {
-                let mut object = Object::new(x, y, '!', "Healing Potion", colors::VIOLET, false);
-                object.item = Some(Item::Heal);
-                object
-            } else if dice < 0.7 + 0.1 {
-                let mut object = Object::new(
-                    x,
-                    y,
-                    '#',
-                    "Scroll of lightning bolt",
-                    colors::LIGHT_YELLOW,
-                    false,
-                );
-                object.item = Some(Item::Lightning);
-                object
-            } else if dice < 0.7 + 0.1 + 0.1 {
-                // create a fireball scroll (10% chance)
-                let mut object =
-                    Object::new(x, y, '#', "scroll of fireball", colors::LIGHT_YELLOW, false);
-                object.item = Some(Item::Fireball);
-                object
-            } else {
-                // create a confuse scroll (15% chance)
-                let mut object = Object::new(
-                    x,
-                    y,
-                    '#',
-                    "scroll of confusion",
-                    colors::LIGHT_YELLOW,
-                    false,
-                );
-                object.item = Some(Item::Confuse);
-                object
-            };
+        },
+        1.41,
+    );
 
-            objects.push(item);
-        }
+    if path.find(from, to) {
+        Some((0..path.len()).filter_map(|i| path.get(i)).collect())
+    } else {
+        None
     }
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Rect {
-    x1: i32,
-    y1: i32,
-    x2: i32,
-    y2: i32,
-}
-
-impl Rect {
-    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
-        Rect {
-            x1: x,
-            y1: y,
-            x2: x + w,
-            y2: y + h,
+fn start_travel(
+    to: (i32, i32),
+    map: &Map,
+    objects: &[Object],
+    messages: &mut Messages,
+) -> Option<Occupation> {
+    match find_travel_path(objects[PLAYER].pos(), to, map, objects) {
+        Some(path) => Some(Occupation::new(Activity::Travel(path), objects)),
+        None => {
+            message(messages, "You don't know a way there.", colors::WHITE);
+            None
         }
     }
-
-    pub fn center(&self) -> (i32, i32) {
-        let center_x = (self.x1 + self.x2) / 2;
-        let center_y = (self.y1 + self.y2) / 2;
-        (center_x, center_y)
-    }
-
-    pub fn intersects_with(&self, other: &Rect) -> bool {
-        // returns true if this rectangle intersects with another one
-        (self.x1 <= other.x2)
-            && (self.x2 >= other.x1)
-            && (self.y1 <= other.y2)
-            && (self.y2 >= other.y1)
-    }
 }
 
-fn create_room(room: Rect, map: &mut Map) {
-    for x in (room.x1 + 1)..room.x2 {
-        for y in (room.y1 + 1)..room.y2 {
-            map[x as usize][y as usize] = Tile::empty();
+/// Ask which of the landmarks the player has seen to travel to, and set off
+/// towards it.
+fn travel_to_feature(
+    tcod: &mut Tcod,
+    map: &Map,
+    objects: &[Object],
+    messages: &mut Messages,
+) -> Option<Occupation> {
+    let known: Vec<&Object> = objects
+        .iter()
+        .filter(|o| o.feature.is_some() && o.last_seen.is_some())
+        .collect();
+    let target = match known.len() {
+        0 => {
+            message(
+                messages,
+                "You don't know of anywhere to travel to.",
+                colors::WHITE,
+            );
+            return None;
         }
-    }
-}
+        1 => known[0],
+        _ => {
+            let options: Vec<&str> = known.iter().map(|o| o.name.as_str()).collect();
+            known[tcod.menu("Travel where?\n", &options, INVENTORY_WIDTH)?]
+        }
+    };
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    for x in cmp::min(x1, x2)..=cmp::max(x1, x2) {
-        map[x as usize][y as usize] = Tile::empty();
+    let to = target.last_seen?;
+    let from = objects[PLAYER].pos();
+    let mut path = if from == to {
+        vec![]
+    } else {
+        match find_travel_path(from, to, map, objects) {
+            Some(path) => path,
+            None => {
+                message(messages, "You don't know a way there.", colors::WHITE);
+                return None;
+            }
+        }
+    };
+    // stop next to things that are in the way, like merchants
+    if target.blocks {
+        path.pop();
     }
-}
-
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    for y in cmp::min(y1, y2)..=cmp::max(y1, y2) {
-        map[x as usize][y as usize] = Tile::empty();
+    if path.is_empty() {
+        message(messages, "You are already there.", colors::WHITE);
+        return None;
     }
+    Some(Occupation::new(Activity::Travel(path), objects))
 }
 
-/// Mutably borrow two *separate* elements from the given slice.
-/// Panics when the indexes are equal or out of bounds.
-fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
-    assert!(first_index != second_index);
-    let split_at_index = cmp::max(first_index, second_index);
-    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
-    if first_index < second_index {
-        (&mut first_slice[first_index], &mut second_slice[0])
-    } else {
-        (&mut second_slice[0], &mut first_slice[second_index])
+fn start_rest(objects: &[Object], messages: &mut Messages) -> Option<Occupation> {
+    let fighter = objects[PLAYER].fighter?;
+    if fighter.hp >= fighter.max_hp {
+        message(messages, "You are already at full health.", colors::RED);
+        return None;
     }
-}
-
-fn ai_take_turn(
-    monster_id: usize,
-    map: &Map,
-    messages: &mut Messages,
-    objects: &mut [Object],
-    tcod: &Tcod,
-) {
-    use Ai::*;
-    if let Some(ai) = objects[monster_id].ai.take() {
-        let new_ai = match ai {
-            Basic => ai_basic(monster_id, map, objects, tcod, messages),
-            Confused {
-                previous_ai,
-                num_turns,
-            } => ai_confused(monster_id, map, objects, messages, previous_ai, num_turns),
-        };
-        objects[monster_id].ai = Some(new_ai)
+    if objects[PLAYER].is_hungry() {
+        message(messages, "You are too hungry to rest.", colors::RED);
+        return None;
     }
+    message(messages, "You sit down to rest.", colors::WHITE);
+    Some(Occupation::new(Activity::Rest, objects))
 }
 
-fn ai_confused(
-    monster_id: usize,
-    map: &Map,
+/// Take the next turn of `occupation`. It ends once the activity is done, or
+/// is interrupted by a visible monster or by the player getting hurt or
+/// hungrier.
+fn continue_occupation(
+    mut occupation: Occupation,
+    map: &mut Map,
     objects: &mut [Object],
     messages: &mut Messages,
-    previous_ai: Box<Ai>,
-    num_turns: i32,
-) -> Ai {
-    if num_turns > 0 {
-        // move in a random idrection, and decrease the number of turns confused
-        move_by(
-            monster_id,
-            rand::thread_rng().gen_range(-1, 2),
-            rand::thread_rng().gen_range(-1, 2),
-            map,
-            objects,
-        );
-        Ai::Confused {
-            previous_ai,
-            num_turns: num_turns - 1,
-        }
-    } else {
+    tcod: &Tcod,
+) -> (Option<Occupation>, PlayerAction) {
+    let verb = occupation.activity.verb();
+    let fighter = match objects[PLAYER].fighter {
+        Some(fighter) => fighter,
+        None => return (None, DidntTakeTurn),
+    };
+    if fighter.hp < occupation.last_hp {
         message(
             messages,
-            format!("The {} is no longer confused!", objects[monster_id].name),
+            format!("You are hurt and stop {}.", verb),
             colors::RED,
         );
+        return (None, DidntTakeTurn);
+    }
+    if objects[PLAYER].hunger() != occupation.last_hunger {
+        message(
+            messages,
+            format!("Your stomach growls and you stop {}.", verb),
+            colors::YELLOW,
+        );
+        return (None, DidntTakeTurn);
+    }
 
-        *previous_ai
+    let monster_in_view = objects
+        .iter()
+        .find(|o| o.is_hostile() && tcod.is_visible(o.x, o.y));
+    if let Some(monster) = monster_in_view {
+        message(
+            messages,
+            format!("You see {} and stop {}.", monster.name, verb),
+            colors::LIGHT_RED,
+        );
+        return (None, DidntTakeTurn);
     }
-}
 
-fn ai_basic(
-    monster_id: usize,
-    map: &Map,
-    objects: &mut [Object],
-    tcod: &Tcod,
-    messages: &mut Messages,
-) -> Ai {
-    // a basic monster takes its turn. If you can see it, it can see you
-    let (monster_x, monster_y) = objects[monster_id].pos();
-    if tcod.fov.is_in_fov(monster_x, monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-            // move towards player if far away
+    let done = match occupation.activity {
+        Activity::Travel(ref mut path) => {
+            let (x, y) = path.remove(0);
             let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(monster_id, player_x, player_y, map, objects);
-        } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
-            // close enough, attack! (if the player is still alive.)
-            let (monster, player) = mut_two(monster_id, PLAYER, objects);
-            monster.attack(messages, player);
+            let opening_door = map[x as usize][y as usize].door == Some(Door::Closed);
+            move_by(
+                PLAYER,
+                x - player_x,
+                y - player_y,
+                map,
+                objects,
+                messages,
+                tcod,
+            );
+            if opening_door {
+                // that turn went into opening the door, step through it next
+                path.insert(0, (x, y));
+            } else if objects[PLAYER].pos() != (x, y) {
+                message(messages, "Something is in the way.", colors::WHITE);
+                return (None, DidntTakeTurn);
+            }
+            path.is_empty()
+        }
+        Activity::Rest => {
+            if fighter.hp >= fighter.max_hp {
+                message(messages, "You feel fully rested.", colors::LIGHT_VIOLET);
+                return (None, DidntTakeTurn);
+            }
+            if objects[PLAYER].is_hungry() {
+                message(messages, "You are too hungry to rest.", colors::RED);
+                return (None, DidntTakeTurn);
+            }
+            false
         }
+    };
+
+    if done {
+        (None, TookTurn)
+    } else {
+        occupation.last_hp = fighter.hp;
+        occupation.last_hunger = objects[PLAYER].hunger();
+        (Some(occupation), TookTurn)
     }
-    Ai::Basic
 }
 
-fn main() {
-    let mut messages = vec![];
-
-    let mut tcod = Tcod {
-        root: Root::initializer()
-            .font("arial10x10.png", FontLayout::Tcod)
-            .font_type(FontType::Greyscale)
-            .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-            .title("Rust/libtcod tutorial")
-            .init(),
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
-        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
-        mouse: Default::default(),
+fn cast_fireball(
+    _inventory_id: usize,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    map: &mut Map,
+    tcod: &mut Tcod,
+) -> UseResult {
+    // ask the player for a target tile to throw a fireball at
+    message(
+        messages,
+        "Left-click a target tile for the fireball, or right-click to cancel.",
+        colors::LIGHT_CYAN,
+    );
+    let blast_radius = Item::Fireball.blast_radius();
+    let (x, y) = match tcod.target_tile(objects, map, messages, None, blast_radius) {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    let from = objects[PLAYER].pos();
+    animate(
+        Animation::Projectile {
+            from,
+            to: (x, y),
+            glyph: '*',
+            color: colors::ORANGE,
+        },
+        tcod,
+        objects,
+        map,
+        messages,
+    );
+    animate(
+        Animation::Burst {
+            center: (x, y),
+            radius: FIREBALL_RADIUS,
+            color: colors::FLAME,
+        },
+        tcod,
+        objects,
+        map,
+        messages,
+    );
+    message(
+        messages,
+        format!(
+            "The fireball explodes, burning everything within {} tiles!",
+            FIREBALL_RADIUS
+        ),
+        colors::ORANGE,
+    );
+
+    for obj in objects {
+        if obj.distance(x, y) <= FIREBALL_RADIUS as f32 && obj.fighter.is_some() {
+            message(
+                messages,
+                format!(
+                    "The {} gets burned for {} hit points.",
+                    obj.name, FIREBALL_DAMAGE
+                ),
+                colors::ORANGE,
+            );
+            obj.take_damage(messages, FIREBALL_DAMAGE);
+        }
+    }
+
+    UseResult::UsedUp
+}
+
+fn cast_confuse(
+    _inventory_id: usize,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    map: &mut Map,
+    tcod: &mut Tcod,
+) -> UseResult {
+    message(
+        messages,
+        "Left-click an enemy to confuse it, or right-click to cancel.",
+        colors::LIGHT_CYAN,
+    );
+    let monster_id = tcod.target_monster(objects, map, messages, Some(CONFUSE_RANGE as f32));
+    if let Some(monster_id) = monster_id {
+        let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
+        // replace the monster's AI with a "confused" one; after
+        // some turns it will restore the old AI
+        objects[monster_id].ai = Some(Ai::Confused {
+            previous_ai: Box::new(old_ai),
+            num_turns: CONFUSE_NUM_TURNS,
+        });
+        message(
+            messages,
+            format!(
+                "The eyes of {} look vacant, as he starts to stumble around!",
+                objects[monster_id].name
+            ),
+            colors::LIGHT_GREEN,
+        );
+        UseResult::UsedUp
+    } else {
+        // no enemy fonud within maximum range
+        message(messages, "No enemy is close enough to strike.", colors::RED);
+        UseResult::Cancelled
+    }
+}
+
+fn cast_lightning(
+    _inventory_id: usize,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    map: &mut Map,
+    tcod: &mut Tcod,
+) -> UseResult {
+    // find closest enemy (inside a maximum range) and damage it
+    let monster_id = closest_monster(LIGHTNING_RANGE, objects, tcod);
+    if let Some(monster_id) = monster_id {
+        animate(
+            Animation::Bolt {
+                from: objects[PLAYER].pos(),
+                to: objects[monster_id].pos(),
+                color: colors::LIGHT_BLUE,
+            },
+            tcod,
+            objects,
+            map,
+            messages,
+        );
+        // zap it!
+        message(
+            messages,
+            format!(
+                "A lightning bolt strikes the {} with a loud thunder! \
+                 The damage is {} hit points.",
+                objects[monster_id].name, LIGHTNING_DAMAGE
+            ),
+            colors::LIGHT_BLUE,
+        );
+        objects[monster_id].take_damage(messages, LIGHTNING_DAMAGE);
+        UseResult::UsedUp
+    } else {
+        // no enemy found within maximum range
+        message(messages, "No enemy is close enough to strike.", colors::RED);
+        UseResult::Cancelled
+    }
+}
+
+/// A spell effect drawn over the map before the spell does anything.
+enum Animation {
+    /// something flying from one tile to another
+    Projectile {
+        from: (i32, i32),
+        to: (i32, i32),
+        glyph: char,
+        color: Color,
+    },
+    /// a flickering line between two tiles
+    Bolt {
+        from: (i32, i32),
+        to: (i32, i32),
+        color: Color,
+    },
+    /// a flash spreading out to fill a circle
+    Burst {
+        center: (i32, i32),
+        radius: i32,
+        color: Color,
+    },
+}
+
+/// The tiles to draw on in one frame of an animation, with what to draw.
+type Frame = Vec<((i32, i32), char, Color)>;
+
+/// Play the animation frame by frame over the map, unless animations are
+/// turned off. It only takes a moment, and doesn't wait for a key.
+fn animate(
+    animation: Animation,
+    tcod: &mut Tcod,
+    objects: &[Object],
+    map: &mut Map,
+    messages: &Messages,
+) {
+    if !tcod.config.animations {
+        return;
+    }
+    let frames: Vec<Frame> = match animation {
+        Animation::Projectile {
+            from,
+            to,
+            glyph,
+            color,
+        } => Line::new(from, to)
+            .map(|tile| vec![(tile, glyph, color)])
+            .collect(),
+        Animation::Bolt { from, to, color } => (0..BOLT_FRAMES)
+            .map(|frame| {
+                let color = if frame % 2 == 0 { color } else { colors::WHITE };
+                Line::new(from, to).map(|tile| (tile, '*', color)).collect()
+            })
+            .collect(),
+        Animation::Burst {
+            center: (x, y),
+            radius,
+            color,
+        } => (0..=radius)
+            .map(|size| {
+                let mut tiles = vec![];
+                for tile_x in (x - size)..=(x + size) {
+                    for tile_y in (y - size)..=(y + size) {
+                        let distance = (((tile_x - x).pow(2) + (tile_y - y).pow(2)) as f32).sqrt();
+                        if distance <= size as f32 {
+                            tiles.push(((tile_x, tile_y), '*', color));
+                        }
+                    }
+                }
+                tiles
+            })
+            .collect(),
+    };
+
+    for frame in frames {
+        render_all(tcod, objects, map, messages, false);
+        let (camera_x, camera_y) = tcod.camera;
+        for ((x, y), glyph, color) in frame {
+            let (screen_x, screen_y) = (x - camera_x, y - camera_y);
+            let on_screen = screen_x >= 0
+                && screen_y >= 0
+                && screen_x < tcod.camera_width()
+                && screen_y < CAMERA_HEIGHT;
+            if on_screen {
+                tcod.root.set_default_foreground(color);
+                tcod.root
+                    .put_char(screen_x, screen_y, glyph, BackgroundFlag::None);
+            }
+        }
+        tcod.root.flush();
+    }
+}
+
+fn cast_identify(
+    inventory: &mut [Object],
+    objects: &mut [Object],
+    messages: &mut Messages,
+    tcod: &mut Tcod,
+    discoveries: &mut Discoveries,
+) -> UseResult {
+    // ask the player which of the items they don't know yet to identify
+    let unknown: Vec<usize> = (0..inventory.len())
+        .filter(|&id| {
+            inventory[id]
+                .item
+                .map(|item| discoveries.is_identified(item))
+                == Some(false)
+        })
+        .collect();
+    if unknown.is_empty() {
+        message(
+            messages,
+            "You have nothing left to identify.",
+            colors::WHITE,
+        );
+        return UseResult::Cancelled;
+    }
+    let names: Vec<String> = unknown
+        .iter()
+        .map(|&id| inventory[id].display_name())
+        .collect();
+    let choice = tcod.menu(
+        "Press the key next to an item to identify it, or any other to cancel.\n",
+        &names,
+        INVENTORY_WIDTH,
+    );
+    match choice.and_then(|choice| inventory[unknown[choice]].item) {
+        Some(item) => {
+            discoveries.identify(item, objects, inventory, messages);
+            UseResult::UsedUp
+        }
+        None => UseResult::Cancelled,
+    }
+}
+
+fn toggle_lantern(lantern: &mut Object, messages: &mut Messages) {
+    if let Some(ref mut light) = lantern.light {
+        if light.fuel == Some(0) {
+            message(messages, "Your lantern is out of fuel.", colors::RED);
+        } else {
+            light.lit = !light.lit;
+            let state = if light.lit { "light" } else { "put out" };
+            message(
+                messages,
+                format!("You {} the {}.", state, lantern.name),
+                colors::LIGHT_AMBER,
+            );
+        }
+    }
+}
+
+/// Burn a turn's worth of fuel in every lit light the player carries.
+fn burn_fuel(inventory: &mut [Object], messages: &mut Messages) {
+    for object in inventory {
+        if let Some(ref mut light) = object.light {
+            if let (true, Some(fuel)) = (light.lit, light.fuel) {
+                light.fuel = Some(fuel - 1);
+                if fuel - 1 == LANTERN_LOW_FUEL {
+                    message(
+                        messages,
+                        format!("Your {} flickers.", object.name),
+                        colors::LIGHT_AMBER,
+                    );
+                } else if fuel - 1 <= 0 {
+                    light.lit = false;
+                    message(
+                        messages,
+                        format!("Your {} goes out.", object.name),
+                        colors::RED,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Age every corpse by a turn, whether it's on the floor or in the pack.
+fn rot_corpses(objects: &mut [Object], inventory: &mut [Object]) {
+    for object in objects.iter_mut().chain(inventory.iter_mut()) {
+        if object.item == Some(Item::Corpse) {
+            object.age += 1;
+        }
+    }
+}
+
+/// Use up a turn's worth of the player's food, warning them as they get
+/// hungrier, and hurt them once there is none left.
+fn digest(player: &mut Object, messages: &mut Messages) {
+    let before = player.hunger();
+    if let Some(ref mut food) = player.food {
+        *food = cmp::max(*food - 1, 0);
+    }
+    let hunger = match player.hunger() {
+        Some(hunger) => hunger,
+        None => return,
+    };
+    if Some(hunger) != before {
+        let warning = match hunger {
+            Hunger::Fed => return,
+            Hunger::Hungry => "You are getting hungry.",
+            Hunger::Weak => "You feel weak with hunger.",
+            Hunger::Starving => "You are starving!",
+        };
+        message(messages, warning, hunger.color());
+    }
+    if hunger == Hunger::Starving {
+        player.take_damage(messages, STARVATION_DAMAGE);
+    }
+}
+
+fn closest_monster(max_range: i32, objects: &mut [Object], tcod: &Tcod) -> Option<usize> {
+    let mut closest_enemy = None;
+    let mut closest_dist = (max_range + 1) as f32;
+
+    for (id, object) in objects.iter().enumerate() {
+        if (id != PLAYER)
+            && object.fighter.is_some()
+            && object.ai.is_some()
+            && tcod.is_visible(object.x, object.y)
+        {
+            let dist = objects[PLAYER].distance_to(object);
+            if dist < closest_dist {
+                closest_enemy = Some(id);
+                closest_dist = dist;
+            }
+        }
+    }
+    closest_enemy
+}
+
+fn cast_heal(
+    _inventory_id: usize,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    map: &mut Map,
+    _tcod: &mut Tcod,
+) -> UseResult {
+    if let Some(fighter) = objects[PLAYER].fighter {
+        if fighter.hp == fighter.max_hp {
+            message(messages, "You are already at full health.", colors::RED);
+            return UseResult::Cancelled;
+        }
+
+        message(
+            messages,
+            "Your wounds start ot feel better!",
+            colors::LIGHT_VIOLET,
+        );
+        objects[PLAYER].heal(HEAL_AMOUNT);
+        return UseResult::UsedUp;
+    }
+    UseResult::Cancelled
+}
+
+fn eat(food_item: &Object, objects: &mut [Object], messages: &mut Messages) -> UseResult {
+    let player = &mut objects[PLAYER];
+    let food = match player.food {
+        Some(food) => food,
+        None => return UseResult::Cancelled,
+    };
+    if food >= FOOD_MAX {
+        message(messages, "You are too full to eat.", colors::RED);
+        return UseResult::Cancelled;
+    }
+
+    let nutrition = match food_item.item {
+        Some(Item::Corpse) if rand::random::<f32>() < food_item.rot_chance() => {
+            // it's been lying on the dungeon floor, after all
+            message(
+                messages,
+                "Ugh, that was rotten! You throw it right back up.",
+                colors::LIGHT_GREEN,
+            );
+            player.take_damage(messages, CORPSE_SICK_DAMAGE);
+            return UseResult::UsedUp;
+        }
+        Some(Item::Corpse) => CORPSE_FOOD,
+        _ => RATION_FOOD,
+    };
+    message(messages, "That hit the spot.", colors::LIGHT_GREEN);
+    player.food = Some(cmp::min(food + nutrition, FOOD_MAX));
+    UseResult::UsedUp
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    kind: TileKind,
+    blocked: bool,
+    block_sight: bool,
+    explored: bool,
+    /// lit by daylight-like ambient light, rather than only by light sources
+    lit: bool,
+    door: Option<Door>,
+    /// the door the player saw here last time, to keep showing it out of
+    /// view; objects remember where they were seen themselves
+    remembered_door: Option<Door>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Door {
+    Open,
+    Closed,
+    /// a closed door that looks like a wall until it's found by searching
+    Secret,
+}
+
+impl Door {
+    /// The character drawn for the door, if it looks like one.
+    pub fn glyph(self) -> Option<(char, Color)> {
+        match self {
+            Door::Closed => Some(('+', colors::SEPIA)),
+            Door::Open => Some(('\'', colors::SEPIA)),
+            Door::Secret => None,
+        }
+    }
+}
+
+/// What a tile is made of, which decides how it looks and what it does to
+/// whoever walks onto it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TileKind {
+    Floor,
+    Wall,
+    ShallowWater,
+    /// washes items out of the player's pack
+    DeepWater,
+    /// burns anything that walks into it
+    Lava,
+    Rubble,
+    /// tall grass, which can be walked through but not seen through
+    Grass,
+}
+
+impl TileKind {
+    pub fn blocks(self) -> bool {
+        self == TileKind::Wall
+    }
+
+    pub fn blocks_sight(self) -> bool {
+        self == TileKind::Wall || self == TileKind::Grass
+    }
+
+    /// Whether walking onto it does harm, so it's best walked around.
+    pub fn is_hazard(self) -> bool {
+        self == TileKind::Lava || self == TileKind::DeepWater
+    }
+
+    /// How many turns it takes to walk onto a tile of this kind. The player
+    /// pays for it with extra turns for the monsters, and monsters with a
+    /// `move_delay`.
+    pub fn move_cost(self) -> i32 {
+        match self {
+            TileKind::ShallowWater | TileKind::Rubble => 2,
+            TileKind::DeepWater => 3,
+            _ => 1,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TileKind::Floor => "floor",
+            TileKind::Wall => "wall",
+            TileKind::ShallowWater => "shallow water",
+            TileKind::DeepWater => "deep water",
+            TileKind::Lava => "lava",
+            TileKind::Rubble => "rubble",
+            TileKind::Grass => "tall grass",
+        }
+    }
+
+    /// The background colors outside and inside the field of view.
+    pub fn colors(self) -> (Color, Color) {
+        match self {
+            TileKind::Floor => (COLOR_DARK_GROUND, COLOR_LIGHT_GROUND),
+            TileKind::Wall => (COLOR_DARK_WALL, COLOR_LIGHT_WALL),
+            TileKind::ShallowWater => (colors::DARKER_AZURE, colors::AZURE),
+            TileKind::DeepWater => (colors::DARKEST_BLUE, colors::DARK_BLUE),
+            TileKind::Lava => (colors::DARKER_RED, colors::FLAME),
+            TileKind::Rubble => (colors::DARKER_GREY, colors::GREY),
+            TileKind::Grass => (colors::DARKER_GREEN, colors::DARK_GREEN),
+        }
+    }
+
+    /// The character drawn on tiles of this kind, if any, and its color.
+    pub fn glyph(self) -> Option<(char, Color)> {
+        match self {
+            TileKind::ShallowWater | TileKind::DeepWater => Some(('~', colors::LIGHT_BLUE)),
+            TileKind::Lava => Some(('~', colors::YELLOW)),
+            TileKind::Rubble => Some((':', colors::LIGHT_GREY)),
+            TileKind::Grass => Some(('"', colors::LIGHT_GREEN)),
+            TileKind::Floor | TileKind::Wall => None,
+        }
+    }
+}
+
+impl Tile {
+    pub fn new(kind: TileKind) -> Self {
+        Tile {
+            kind,
+            blocked: kind.blocks(),
+            block_sight: kind.blocks_sight(),
+            explored: false,
+            lit: true,
+            door: None,
+            remembered_door: None,
+        }
+    }
+
+    pub fn empty() -> Self {
+        Tile::new(TileKind::Floor)
+    }
+
+    pub fn wall() -> Self {
+        Tile::new(TileKind::Wall)
+    }
+
+    pub fn door(door: Door) -> Self {
+        let open = door == Door::Open;
+        Tile {
+            blocked: !open,
+            block_sight: !open,
+            door: Some(door),
+            ..Tile::empty()
+        }
+    }
+
+    /// Whether something can get through, if need be by opening a door.
+    /// Secret doors count as walls until they are found.
+    pub fn is_walkable(&self) -> bool {
+        !self.blocked || self.door == Some(Door::Closed)
+    }
+}
+
+type Messages = Vec<(String, Color)>;
+type Map = Vec<Vec<Tile>>;
+/// The color and amount of light reaching every tile.
+type LightMap = Vec<Vec<Color>>;
+
+/// Turn the tile at (x, y) into a door in the given state, keeping whether
+/// it has been explored and what the player remembers of it.
+fn set_door(x: i32, y: i32, door: Door, map: &mut Map) {
+    let Tile {
+        explored,
+        lit,
+        remembered_door,
+        ..
+    } = map[x as usize][y as usize];
+    map[x as usize][y as usize] = Tile {
+        explored,
+        lit,
+        remembered_door,
+        ..Tile::door(door)
+    };
+}
+
+fn message<T: Into<String>>(messages: &mut Messages, message: T, color: Color) {
+    if messages.len() == MSG_HEIGHT {
+        messages.remove(0);
+    }
+
+    messages.push((message.into(), color))
+}
+
+/// The algorithms available to lay out a level.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MapGenerator {
+    /// random non-overlapping rooms, each connected to the one before it
+    Rooms,
+    /// rooms spread out by binary space partitioning, connected to their siblings
+    Bsp,
+    /// one organic cave, grown with a cellular automaton
+    Caves,
+}
+
+impl MapGenerator {
+    /// Choose how to lay out the given dungeon level, going by the
+    /// `map_generators` setting.
+    fn for_level(dungeon_level: u32, choices: &[Option<MapGenerator>]) -> Self {
+        let index = cmp::min(dungeon_level as usize, choices.len()).saturating_sub(1);
+        if let Some(&Some(generator)) = choices.get(index) {
+            return generator;
+        }
+        match rand::thread_rng().gen_range(0, 3) {
+            0 => MapGenerator::Rooms,
+            1 => MapGenerator::Bsp,
+            _ => MapGenerator::Caves,
+        }
+    }
+}
+
+/// Where things go on a freshly generated map.
+struct Layout {
+    /// areas to scatter monsters and items over
+    rooms: Vec<Rect>,
+    start: (i32, i32),
+    stairs: (i32, i32),
+}
+
+impl Layout {
+    /// Start in the first room and put the stairs in the last one.
+    fn from_rooms(rooms: Vec<Rect>) -> Self {
+        let start = rooms.first().map_or((0, 0), |room| room.center());
+        let stairs = rooms.last().map_or(start, |room| room.center());
+        Layout {
+            rooms,
+            start,
+            stairs,
+        }
+    }
+}
+
+fn make_map(
+    objects: &mut Vec<Object>,
+    dungeon_level: u32,
+    discoveries: &Discoveries,
+    config: &Config,
+) -> Map {
+    let generator = MapGenerator::for_level(dungeon_level, &config.map_generators);
+    let (mut map, layout) = loop {
+        // fill map with "blocked" tiles
+        let mut map = vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+
+        let layout = match generator {
+            MapGenerator::Rooms => Layout::from_rooms(make_rooms(&mut map)),
+            MapGenerator::Bsp => Layout::from_rooms(make_bsp_rooms(&mut map)),
+            MapGenerator::Caves => make_caves(&mut map),
+        };
+
+        // doors go in first, so that a secret one can't cut off a room
+        if generator != MapGenerator::Caves {
+            place_doors(&layout.rooms, &mut map);
+        }
+        // throw away maps that can't be fixed and generate a new one
+        if repair_map(&mut map, &layout).is_ok() {
+            place_terrain(&layout, &mut map);
+            break (map, layout);
+        }
+    };
+
+    // place the player first, so nothing gets generated on top of them
+    objects[PLAYER].set_pos(layout.start.0, layout.start.1);
+
+    let (stairs_x, stairs_y) = layout.stairs;
+    let mut stairs = Object::new(stairs_x, stairs_y, '>', "stairs", colors::WHITE, false);
+    stairs.description = "Stairs leading deeper into the dungeon.".into();
+    stairs.always_visible = true;
+    stairs.feature = Some(Feature::Stairs);
+    objects.push(stairs);
+
+    for &room in &layout.rooms {
+        place_objects(room, &map, objects, discoveries);
+        place_gold(room, &map, objects, dungeon_level);
+        place_trap(room, &map, objects);
+    }
+    if dungeon_level > 1 && layout.rooms.len() > 1 && rand::random::<f32>() < MERCHANT_CHANCE {
+        // not in the first room, where the player arrives
+        let room = layout.rooms[rand::thread_rng().gen_range(1, layout.rooms.len())];
+        place_merchant(room, &map, objects, discoveries);
+    }
+
+    // deeper levels may have no light but what's brought along
+    if dungeon_level > 1 && rand::random::<f32>() < DARK_LEVEL_CHANCE {
+        for tile in map.iter_mut().flat_map(|column| column.iter_mut()) {
+            tile.lit = false;
+        }
+    }
+    place_lights(&layout.rooms, generator, &map, objects);
+
+    map
+}
+
+/// Hang torches on the walls of some rooms, and grow glowing fungus in
+/// others. Caves have no walls to hang torches on, so they get more fungus.
+fn place_lights(rooms: &[Rect], generator: MapGenerator, map: &Map, objects: &mut Vec<Object>) {
+    let fungus_chance = if generator == MapGenerator::Caves {
+        FUNGUS_CHANCE * 3.0
+    } else {
+        FUNGUS_CHANCE
+    };
+
+    for room in rooms {
+        if generator != MapGenerator::Caves && rand::random::<f32>() < WALL_TORCH_CHANCE {
+            // somewhere along the top or bottom wall
+            let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+            let y = if rand::random() { room.y1 } else { room.y2 };
+            let tile = &map[x as usize][y as usize];
+            if tile.kind == TileKind::Wall && tile.door.is_none() {
+                let mut torch = Object::new(x, y, '*', "wall torch", colors::AMBER, false);
+                torch.description = "A torch burning in an iron bracket.".into();
+                torch.always_visible = true;
+                torch.light = Some(Light {
+                    radius: WALL_TORCH_RADIUS,
+                    color: colors::LIGHT_AMBER,
+                    lit: true,
+                    fuel: None,
+                });
+                objects.push(torch);
+            }
+        }
+
+        if rand::random::<f32>() < fungus_chance {
+            let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+            let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+            if !map[x as usize][y as usize].blocked {
+                let mut fungus =
+                    Object::new(x, y, ',', "glowing fungus", colors::LIGHT_CHARTREUSE, false);
+                fungus.description = "A patch of fungus giving off a faint green glow.".into();
+                fungus.light = Some(Light {
+                    radius: FUNGUS_RADIUS,
+                    color: colors::LIGHT_CHARTREUSE,
+                    lit: true,
+                    fuel: None,
+                });
+                objects.push(fungus);
+            }
+        }
+    }
+}
+
+/// Work out how much light reaches every tile, from ambient light, the
+/// light sources lying around and whatever the player carries.
+fn compute_lightmap(
+    map: &Map,
+    objects: &[Object],
+    inventory: &[Object],
+    fov_algorithm: FovAlgorithm,
+) -> LightMap {
+    let mut lightmap = vec![vec![colors::BLACK; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if map[x as usize][y as usize].lit {
+                lightmap[x as usize][y as usize] = colors::WHITE;
+            }
+        }
+    }
+
+    let (player_x, player_y) = objects[PLAYER].pos();
+    // even in the dark the player can make out what's right next to them
+    let mut sources = vec![(player_x, player_y, PLAYER_DARK_SIGHT)];
+    for object in objects {
+        if let Some(light) = object.light.filter(|light| light.lit) {
+            sources.push((object.x, object.y, light));
+        }
+    }
+    for object in inventory {
+        if let Some(light) = object.light.filter(|light| light.lit) {
+            sources.push((player_x, player_y, light));
+        }
+    }
+
+    let mut fov = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+    update_fov_map(map, &mut fov);
+    for (source_x, source_y, light) in sources {
+        fov.compute_fov(
+            source_x,
+            source_y,
+            light.radius,
+            FOV_LIGHT_WALLS,
+            fov_algorithm,
+        );
+        for x in
+            cmp::max(0, source_x - light.radius)..cmp::min(MAP_WIDTH, source_x + light.radius + 1)
+        {
+            for y in cmp::max(0, source_y - light.radius)
+                ..cmp::min(MAP_HEIGHT, source_y + light.radius + 1)
+            {
+                if fov.is_in_fov(x, y) {
+                    // fade out linearly towards the edge of the light
+                    let distance = (((x - source_x).pow(2) + (y - source_y).pow(2)) as f32).sqrt();
+                    let intensity = 1.0 - distance / (light.radius + 1) as f32;
+                    if intensity > 0.0 {
+                        let tile_light = &mut lightmap[x as usize][y as usize];
+                        *tile_light = *tile_light + light.color * intensity;
+                    }
+                }
+            }
+        }
+    }
+    lightmap
+}
+
+/// How bright a light color is, from 0 to 1.
+fn light_intensity(light: Color) -> f32 {
+    cmp::max(light.r, cmp::max(light.g, light.b)) as f32 / 255.0
+}
+
+/// Carve random non-overlapping rooms into `map`, each connected to the
+/// previous one, and return them in the order they were created.
+fn make_rooms(map: &mut Map) -> Vec<Rect> {
+    let mut rooms: Vec<Rect> = vec![];
+
+    for _ in 0..MAX_ROOMS {
+        // random width and height
+        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, ROOM_MAX_SIZE + 1);
+        // random position without going out of the boundaries of the map
+        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
+        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+        let new_room = Rect::new(x, y, w, h);
+
+        // run through the other rooms and see if they intersect with this one
+        let failed = rooms
+            .iter()
+            .any(|other_room| new_room.intersects_with(other_room));
+
+        if !failed {
+            // this means there are no intersections, so this room is valid
+
+            // "paint" it to the map's tiles
+            create_room(new_room, map);
+
+            // all rooms after the first:
+            // connect it to the previous room with a tunnel
+            if let Some(&prev_room) = rooms.last() {
+                connect_rooms(prev_room, new_room, map);
+            }
+            // finally, append the new room to the list
+            rooms.push(new_room);
+        }
+    }
+
+    rooms
+}
+
+/// Recursively split the map in two and place a room in every resulting
+/// area, which spreads the rooms out more evenly than `make_rooms`.
+fn make_bsp_rooms(map: &mut Map) -> Vec<Rect> {
+    let mut rooms = vec![];
+    // leave the last row and column as walls, like `make_rooms` does
+    let whole_map = Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1);
+    bsp_split(whole_map, BSP_DEPTH, map, &mut rooms);
+    rooms
+}
+
+/// Split `area` until `depth` runs out or it gets too small, then put a room
+/// in it. The two halves of every split get connected with a tunnel, so
+/// this returns one of the rooms in `area` for its sibling to connect to.
+fn bsp_split(area: Rect, depth: i32, map: &mut Map, rooms: &mut Vec<Rect>) -> Rect {
+    let width = area.x2 - area.x1;
+    let height = area.y2 - area.y1;
+    let can_split_x = width >= 2 * ROOM_MIN_SIZE;
+    let can_split_y = height >= 2 * ROOM_MIN_SIZE;
+
+    if depth == 0 || !(can_split_x || can_split_y) {
+        // this is a leaf: put a room of random size somewhere inside it
+        let w = rand::thread_rng().gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, width) + 1);
+        let h = rand::thread_rng().gen_range(ROOM_MIN_SIZE, cmp::min(ROOM_MAX_SIZE, height) + 1);
+        let x = rand::thread_rng().gen_range(area.x1, area.x2 - w + 1);
+        let y = rand::thread_rng().gen_range(area.y1, area.y2 - h + 1);
+        let room = Rect::new(x, y, w, h);
+        create_room(room, map);
+        rooms.push(room);
+        return room;
+    }
+
+    // prefer cutting across the longer side, so the areas stay roughly square
+    let split_x = if can_split_x && can_split_y {
+        width > height || (width == height && rand::random())
+    } else {
+        can_split_x
+    };
+    let (first, second) = if split_x {
+        let x = rand::thread_rng().gen_range(area.x1 + ROOM_MIN_SIZE, area.x2 - ROOM_MIN_SIZE + 1);
+        (
+            Rect::new(area.x1, area.y1, x - area.x1, height),
+            Rect::new(x, area.y1, area.x2 - x, height),
+        )
+    } else {
+        let y = rand::thread_rng().gen_range(area.y1 + ROOM_MIN_SIZE, area.y2 - ROOM_MIN_SIZE + 1);
+        (
+            Rect::new(area.x1, area.y1, width, y - area.y1),
+            Rect::new(area.x1, y, width, area.y2 - y),
+        )
+    };
+
+    let first_room = bsp_split(first, depth - 1, map, rooms);
+    let second_room = bsp_split(second, depth - 1, map, rooms);
+    connect_rooms(first_room, second_room, map);
+
+    if rand::random() {
+        first_room
+    } else {
+        second_room
+    }
+}
+
+/// Grow a cave with a cellular automaton: start from random noise, smooth it
+/// out a few times, then keep only the largest connected area.
+fn make_caves(map: &mut Map) -> Layout {
+    loop {
+        // random noise, keeping the border solid
+        for x in 1..(MAP_WIDTH - 1) {
+            for y in 1..(MAP_HEIGHT - 1) {
+                map[x as usize][y as usize] = if rand::random::<f32>() < CAVE_FLOOR_CHANCE {
+                    Tile::empty()
+                } else {
+                    Tile::wall()
+                };
+            }
+        }
+
+        for _ in 0..CAVE_SMOOTHING_STEPS {
+            smooth_caves(map);
+        }
+
+        // find the largest connected area and fill in everything else
+        let mut largest: Vec<(i32, i32)> = vec![];
+        let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                if map[x as usize][y as usize].blocked || seen[x as usize][y as usize] {
+                    continue;
+                }
+                let region = flood_fill(map, (x, y));
+                for &(rx, ry) in &region {
+                    seen[rx as usize][ry as usize] = true;
+                }
+                if region.len() > largest.len() {
+                    largest = region;
+                }
+            }
+        }
+        if (largest.len() as i32) < CAVE_MIN_FLOOR {
+            // too cramped, try again
+            continue;
+        }
+        let mut keep = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for &(x, y) in &largest {
+            keep[x as usize][y as usize] = true;
+        }
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                if !keep[x as usize][y as usize] {
+                    map[x as usize][y as usize] = Tile::wall();
+                }
+            }
+        }
+
+        // start anywhere, and put the stairs as far away as possible
+        let start = largest[rand::thread_rng().gen_range(0, largest.len())];
+        let stairs = *flood_fill(map, start).last().unwrap_or(&start);
+
+        // chop the cave into room-sized areas for monsters and items
+        let mut rooms = vec![];
+        for x in (0..MAP_WIDTH - 1).step_by(ROOM_MAX_SIZE as usize) {
+            for y in (0..MAP_HEIGHT - 1).step_by(ROOM_MAX_SIZE as usize) {
+                let w = cmp::min(ROOM_MAX_SIZE, MAP_WIDTH - 1 - x);
+                let h = cmp::min(ROOM_MAX_SIZE, MAP_HEIGHT - 1 - y);
+                if w < 2 || h < 2 {
+                    continue;
+                }
+                let area = Rect::new(x, y, w, h);
+                let has_floor = ((x + 1)..(x + w))
+                    .any(|x| ((y + 1)..(y + h)).any(|y| !map[x as usize][y as usize].blocked));
+                if has_floor {
+                    rooms.push(area);
+                }
+            }
+        }
+
+        return Layout {
+            rooms,
+            start,
+            stairs,
+        };
+    }
+}
+
+/// One step of the cave automaton: a tile becomes a wall when most of its
+/// neighbours are walls, and floor when most of them are floor.
+fn smooth_caves(map: &mut Map) {
+    let previous = map.clone();
+    for x in 1..(MAP_WIDTH - 1) {
+        for y in 1..(MAP_HEIGHT - 1) {
+            let mut walls = 0;
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    if (dx, dy) != (0, 0) && previous[(x + dx) as usize][(y + dy) as usize].blocked
+                    {
+                        walls += 1;
+                    }
+                }
+            }
+            if walls >= 5 {
+                map[x as usize][y as usize] = Tile::wall();
+            } else if walls <= 3 {
+                map[x as usize][y as usize] = Tile::empty();
+            }
+        }
+    }
+}
+
+/// Every walkable tile reachable from `start` by orthogonal steps, in
+/// breadth-first order, so the last one is the farthest away.
+fn flood_fill(map: &Map, start: (i32, i32)) -> Vec<(i32, i32)> {
+    let mut seen = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut reached = vec![];
+    if !map[start.0 as usize][start.1 as usize].is_walkable() {
+        return reached;
+    }
+
+    seen[start.0 as usize][start.1 as usize] = true;
+    reached.push(start);
+    let mut next = 0;
+    while next < reached.len() {
+        let (x, y) = reached[next];
+        next += 1;
+        for &(dx, dy) in &[(0, -1), (0, 1), (-1, 0), (1, 0)] {
+            let (nx, ny) = (x + dx, y + dy);
+            if nx < 0 || ny < 0 || nx >= MAP_WIDTH || ny >= MAP_HEIGHT {
+                continue;
+            }
+            if !seen[nx as usize][ny as usize] && map[nx as usize][ny as usize].is_walkable() {
+                seen[nx as usize][ny as usize] = true;
+                reached.push((nx, ny));
+            }
+        }
+    }
+    reached
+}
+
+/// Put doors where tunnels enter rooms through a one tile wide gap in
+/// their walls. Some of them are secret.
+fn place_doors(rooms: &[Rect], map: &mut Map) {
+    for room in rooms {
+        let mut gaps = vec![];
+        // in the top and bottom walls the gap needs walls to its left and right,
+        // in the left and right walls above and below it
+        for x in (room.x1 + 1)..room.x2 {
+            for &y in &[room.y1, room.y2] {
+                gaps.push(((x, y), (x - 1, y), (x + 1, y)));
+            }
+        }
+        for y in (room.y1 + 1)..room.y2 {
+            for &x in &[room.x1, room.x2] {
+                gaps.push(((x, y), (x, y - 1), (x, y + 1)));
+            }
+        }
+
+        for ((x, y), (ax, ay), (bx, by)) in gaps {
+            let is_gap = !map[x as usize][y as usize].blocked
+                && map[ax as usize][ay as usize].blocked
+                && map[bx as usize][by as usize].blocked;
+            if is_gap && rand::random::<f32>() < DOOR_CHANCE {
+                let door = if rand::random::<f32>() < SECRET_DOOR_CHANCE {
+                    Door::Secret
+                } else {
+                    Door::Closed
+                };
+                set_door(x, y, door, map);
+            }
+        }
+    }
+}
+
+/// Scatter patches of water, lava, rubble and grass over some of the rooms,
+/// keeping the start and the stairs clear.
+fn place_terrain(layout: &Layout, map: &mut Map) {
+    for room in &layout.rooms {
+        if rand::random::<f32>() >= TERRAIN_CHANCE {
+            continue;
+        }
+        let kind = match rand::thread_rng().gen_range(0, 10) {
+            0..=2 => TileKind::ShallowWater,
+            3 => TileKind::DeepWater,
+            4 => TileKind::Lava,
+            5..=6 => TileKind::Rubble,
+            _ => TileKind::Grass,
+        };
+
+        // wander around the room, covering the floor on the way
+        let (mut x, mut y) = room.center();
+        for _ in 0..TERRAIN_PATCH_SIZE {
+            let tile = &mut map[x as usize][y as usize];
+            let keep_clear = (x, y) == layout.start || (x, y) == layout.stairs;
+            if tile.kind == TileKind::Floor && tile.door.is_none() && !keep_clear {
+                *tile = Tile::new(kind);
+            }
+            x = (x + rand::thread_rng().gen_range(-1, 2))
+                .max(room.x1 + 1)
+                .min(room.x2 - 1);
+            y = (y + rand::thread_rng().gen_range(-1, 2))
+                .max(room.y1 + 1)
+                .min(room.y2 - 1);
+        }
+    }
+}
+
+/// Reasons a generated map can't be played on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MapProblem {
+    /// the player would start inside a wall
+    StartBlocked,
+    /// none of the room's floor can be reached from the start
+    RoomUnreachable(Rect),
+    StairsUnreachable,
+}
+
+/// Check that every room and the stairs can be walked to from the start.
+fn validate_map(map: &Map, layout: &Layout) -> Result<(), MapProblem> {
+    let (start_x, start_y) = layout.start;
+    if map[start_x as usize][start_y as usize].blocked {
+        return Err(MapProblem::StartBlocked);
+    }
+
+    let mut reachable = vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for (x, y) in flood_fill(map, layout.start) {
+        reachable[x as usize][y as usize] = true;
+    }
+
+    let unreachable_room = layout.rooms.iter().find(|room| {
+        !((room.x1 + 1)..room.x2)
+            .any(|x| ((room.y1 + 1)..room.y2).any(|y| reachable[x as usize][y as usize]))
+    });
+    if let Some(&room) = unreachable_room {
+        return Err(MapProblem::RoomUnreachable(room));
+    }
+
+    let (stairs_x, stairs_y) = layout.stairs;
+    if !reachable[stairs_x as usize][stairs_y as usize] {
+        return Err(MapProblem::StairsUnreachable);
+    }
+    Ok(())
+}
+
+/// Tunnel from the start to anything `validate_map` finds unreachable.
+/// Fails if the map is beyond repair and should be generated again.
+fn repair_map(map: &mut Map, layout: &Layout) -> Result<(), MapProblem> {
+    // every tunnel fixes at least one room or the stairs
+    for _ in 0..=layout.rooms.len() {
+        match validate_map(map, layout) {
+            Ok(()) => return Ok(()),
+            Err(MapProblem::StartBlocked) => return Err(MapProblem::StartBlocked),
+            Err(MapProblem::RoomUnreachable(room)) => dig_tunnel(layout.start, room.center(), map),
+            Err(MapProblem::StairsUnreachable) => dig_tunnel(layout.start, layout.stairs, map),
+        }
+    }
+    validate_map(map, layout)
+}
+
+/// Dig an L-shaped tunnel between the centers of two rooms.
+fn connect_rooms(from: Rect, to: Rect, map: &mut Map) {
+    dig_tunnel(from.center(), to.center(), map);
+}
+
+/// Dig an L-shaped tunnel between two points.
+fn dig_tunnel(from: (i32, i32), to: (i32, i32), map: &mut Map) {
+    let (prev_x, prev_y) = from;
+    let (new_x, new_y) = to;
+
+    // draw a coin (random bool value -- either true or false)
+    if rand::random() {
+        // first move horizontally, then vertically
+        create_h_tunnel(prev_x, new_x, prev_y, map);
+        create_v_tunnel(prev_y, new_y, new_x, map);
+    } else {
+        // first move vertically, then horizontally
+        create_v_tunnel(prev_y, new_y, prev_x, map);
+        create_h_tunnel(prev_x, new_x, new_y, map);
+    }
+}
+
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, discoveries: &Discoveries) {
+    let num_monsters = rand::thread_rng().gen_range(0, MAX_ROOM_MONSTERS + 1);
+    let num_items = rand::thread_rng().gen_range(0, MAX_ROOM_ITEMS + 1);
+
+    for _ in 0..num_monsters {
+        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+
+        if !is_blocked(x, y, map, objects) {
+            let mut monster = if rand::random::<f32>() < 0.8 {
+                // create an orc
+                let mut orc = Object::new(x, y, 'o', "Orc", colors::DESATURATED_GREEN, true);
+                orc.description = "A brutish humanoid, quick to anger and slow to heal.".into();
+                orc.fighter = Some(Fighter {
+                    max_hp: 10,
+                    hp: 10,
+                    defense: 0,
+                    power: 3,
+                    sight_radius: 8,
+                    regen_turns: 10,
+                    regen_progress: 0,
+                    on_death: DeathCallback::Monster,
+                });
+                orc.ai = Some(Ai::Basic);
+                orc.loot = ORC_LOOT;
+                orc
+            } else {
+                let mut troll = Object::new(x, y, 'T', "Troll", colors::DARKER_GREEN, true);
+                troll.description =
+                    "A hulking, short-sighted brute whose wounds close as you watch.".into();
+                troll.fighter = Some(Fighter {
+                    max_hp: 16,
+                    hp: 16,
+                    defense: 1,
+                    power: 4,
+                    sight_radius: 5,
+                    regen_turns: 3,
+                    regen_progress: 0,
+                    on_death: DeathCallback::Monster,
+                });
+                troll.ai = Some(Ai::Basic);
+                troll.loot = TROLL_LOOT;
+                troll
+            };
+            monster.alive = true;
+
+            objects.push(monster);
+        }
+    }
+
+    for _ in 0..num_items {
+        let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+        let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+
+        if !is_blocked(x, y, map, objects) {
+            objects.push(make_item(random_item(), x, y, discoveries));
+        }
+    }
+}
+
+/// Pick a random kind of item.
+fn random_item() -> Item {
+    let dice = rand::random::<f32>();
+    if dice < 0.45 {
+        // healing potion (45% chance)
+        Item::Heal
+    } else if dice < 0.45 + 0.1 {
+        // food ration (10% chance)
+        Item::Ration
+    } else if dice < 0.45 + 0.1 + 0.1 {
+        // lightning bolt scroll (10% chance)
+        Item::Lightning
+    } else if dice < 0.45 + 0.1 + 0.1 + 0.1 {
+        // fireball scroll (10% chance)
+        Item::Fireball
+    } else if dice < 0.45 + 0.1 + 0.1 + 0.1 + 0.05 {
+        // lantern (5% chance)
+        Item::Lantern
+    } else if dice < 0.45 + 0.1 + 0.1 + 0.1 + 0.05 + 0.1 {
+        // identify scroll (10% chance)
+        Item::Identify
+    } else {
+        // confuse scroll (10% chance)
+        Item::Confuse
+    }
+}
+
+/// Create an item of the given kind at (x, y).
+fn make_item(item: Item, x: i32, y: i32, discoveries: &Discoveries) -> Object {
+    // potions and scrolls go by what they look like until identified
+    let name = discoveries.name(item);
+    let (char, color, description) = match item {
+        Item::Heal => ('!', colors::VIOLET, "A small vial of thick red liquid."),
+        Item::Lightning => (
+            '#',
+            colors::LIGHT_YELLOW,
+            "The parchment crackles under your fingers.",
+        ),
+        Item::Fireball => (
+            '#',
+            colors::LIGHT_YELLOW,
+            "The parchment is warm to the touch.",
+        ),
+        Item::Identify => (
+            '#',
+            colors::LIGHT_YELLOW,
+            "The ink shimmers, as if it knows something you don't.",
+        ),
+        Item::Confuse => (
+            '#',
+            colors::LIGHT_YELLOW,
+            "The runes on it seem to squirm as you read them.",
+        ),
+        Item::Ration => (
+            '%',
+            colors::LIGHT_SEPIA,
+            "Dried meat and hard bread, wrapped in cloth.",
+        ),
+        Item::Corpse => ('%', colors::DARK_RED, "It smells as bad as it looks."),
+        Item::Lantern => return make_lantern(x, y),
+    };
+    let mut object = Object::new(x, y, char, &name, color, false);
+    object.description = description.into();
+    object.item = Some(item);
+    object
+}
+
+/// Roll on the loot table of everything that died since last time, and drop
+/// what comes up on or next to its remains.
+fn drop_loot(
+    objects: &mut Vec<Object>,
+    map: &Map,
+    messages: &mut Messages,
+    discoveries: &Discoveries,
+    dungeon_level: u32,
+) {
+    for id in 0..objects.len() {
+        if objects[id].alive || objects[id].loot.is_empty() {
+            continue;
+        }
+        let table = objects[id].loot;
+        // only once, even if nothing dropped
+        objects[id].loot = &[];
+
+        let weight = |drop: &LootDrop| drop.weight + drop.weight_per_level * (dungeon_level - 1);
+        let total: u32 = table.iter().map(weight).sum();
+        if total == 0 {
+            continue;
+        }
+        let mut roll = rand::thread_rng().gen_range(0, total);
+        let mut loot = Loot::Nothing;
+        for drop in table {
+            if roll < weight(drop) {
+                loot = drop.loot;
+                break;
+            }
+            roll -= weight(drop);
+        }
+
+        let (x, y) = loot_position(id, map, objects);
+        let dropped = match loot {
+            Loot::Nothing => continue,
+            Loot::Gold => {
+                let amount =
+                    rand::thread_rng().gen_range(1, GOLD_PER_LEVEL + 1) * dungeon_level as i32;
+                make_gold(x, y, amount)
+            }
+            Loot::Item(item) => make_item(item, x, y, discoveries),
+        };
+        message(
+            messages,
+            format!(
+                "Something falls out of the {}: {}.",
+                objects[id].name, dropped.name
+            ),
+            colors::LIGHT_GREY,
+        );
+        objects.push(dropped);
+    }
+}
+
+/// Where to put something object `id` dropped: on its own tile if nothing
+/// else is lying there, otherwise on a free tile next to it.
+fn loot_position(id: usize, map: &Map, objects: &[Object]) -> (i32, i32) {
+    let (x, y) = objects[id].pos();
+    let is_free = |(x, y): (i32, i32)| {
+        x >= 0
+            && y >= 0
+            && x < MAP_WIDTH
+            && y < MAP_HEIGHT
+            && !map[x as usize][y as usize].blocked
+            && !objects.iter().enumerate().any(|(other_id, o)| {
+                // its own remains don't count
+                other_id != id && o.pos() == (x, y) && (o.blocks || o.item.is_some() || o.gold > 0)
+            })
+    };
+    let neighbours = (-1..=1).flat_map(|dx| (-1..=1).map(move |dy| (x + dx, y + dy)));
+    std::iter::once((x, y))
+        .chain(neighbours)
+        .find(|&pos| is_free(pos))
+        .unwrap_or((x, y))
+}
+
+fn place_gold(room: Rect, map: &Map, objects: &mut Vec<Object>, dungeon_level: u32) {
+    if rand::random::<f32>() >= GOLD_CHANCE {
+        return;
+    }
+    let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+    let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+    if !is_blocked(x, y, map, objects) {
+        let amount = rand::thread_rng().gen_range(1, GOLD_PER_LEVEL + 1) * dungeon_level as i32;
+        objects.push(make_gold(x, y, amount));
+    }
+}
+
+fn make_gold(x: i32, y: i32, amount: i32) -> Object {
+    let mut gold = Object::new(x, y, '$', "gold pieces", colors::GOLD, false);
+    gold.description = format!("A pile of {} gold coins.", amount);
+    gold.gold = amount;
+    gold
+}
+
+/// Put a merchant with a few random items for sale in the middle of the room.
+fn place_merchant(room: Rect, map: &Map, objects: &mut Vec<Object>, discoveries: &Discoveries) {
+    let (x, y) = room.center();
+    if is_blocked(x, y, map, objects) {
+        return;
+    }
+    let mut merchant = Object::new(x, y, '@', "merchant", colors::GOLD, true);
+    merchant.description = "A travelling merchant, happy to buy and sell.".into();
+    merchant.alive = true;
+    merchant.ai = Some(Ai::Merchant);
+    merchant.feature = Some(Feature::Shop);
+    merchant.gold = MERCHANT_GOLD;
+    for _ in 0..rand::thread_rng().gen_range(MERCHANT_MIN_WARES, MERCHANT_MAX_WARES + 1) {
+        add_item(
+            &mut merchant.wares,
+            make_item(random_item(), 0, 0, discoveries),
+        );
+    }
+    objects.push(merchant);
+}
+
+fn place_trap(room: Rect, map: &Map, objects: &mut Vec<Object>) {
+    if rand::random::<f32>() >= TRAP_CHANCE {
+        return;
+    }
+    let x = rand::thread_rng().gen_range(room.x1 + 1, room.x2);
+    let y = rand::thread_rng().gen_range(room.y1 + 1, room.y2);
+    // keep traps off the stairs, the player and the walls
+    let occupied = objects.iter().any(|object| object.pos() == (x, y));
+    if occupied || map[x as usize][y as usize].blocked {
+        return;
+    }
+
+    let dice = rand::random::<f32>();
+    let (kind, name, color) = if dice < 0.3 {
+        (TrapKind::Dart, "dart trap", colors::LIGHT_GREY)
+    } else if dice < 0.3 + 0.2 {
+        (TrapKind::Pit, "pit", colors::DARK_SEPIA)
+    } else if dice < 0.3 + 0.2 + 0.2 {
+        (TrapKind::BearTrap, "bear trap", colors::GREY)
+    } else if dice < 0.3 + 0.2 + 0.2 + 0.15 {
+        (TrapKind::Teleport, "teleport trap", colors::LIGHT_VIOLET)
+    } else {
+        (TrapKind::Alarm, "alarm trap", colors::LIGHT_RED)
+    };
+    let mut trap = Object::new(x, y, '^', name, color, false);
+    trap.description = kind.description();
+    trap.trap = Some(Trap { kind, hidden: true });
+    objects.push(trap);
+}
+
+fn make_lantern(x: i32, y: i32) -> Object {
+    let mut lantern = Object::new(x, y, '(', "lantern", colors::LIGHT_AMBER, false);
+    lantern.description = "A brass oil lantern.".into();
+    lantern.item = Some(Item::Lantern);
+    lantern.light = Some(Light {
+        radius: LANTERN_RADIUS,
+        color: colors::LIGHTEST_AMBER,
+        lit: false,
+        fuel: Some(LANTERN_FUEL),
+    });
+    lantern
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Rect {
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect {
+            x1: x,
+            y1: y,
+            x2: x + w,
+            y2: y + h,
+        }
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        let center_x = (self.x1 + self.x2) / 2;
+        let center_y = (self.y1 + self.y2) / 2;
+        (center_x, center_y)
+    }
+
+    pub fn intersects_with(&self, other: &Rect) -> bool {
+        // returns true if this rectangle intersects with another one
+        (self.x1 <= other.x2)
+            && (self.x2 >= other.x1)
+            && (self.y1 <= other.y2)
+            && (self.y2 >= other.y1)
+    }
+}
+
+fn create_room(room: Rect, map: &mut Map) {
+    for x in (room.x1 + 1)..room.x2 {
+        for y in (room.y1 + 1)..room.y2 {
+            map[x as usize][y as usize] = Tile::empty();
+        }
+    }
+}
+
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    for x in cmp::min(x1, x2)..=cmp::max(x1, x2) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    for y in cmp::min(y1, y2)..=cmp::max(y1, y2) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+/// Mutably borrow two *separate* elements from the given slice.
+/// Panics when the indexes are equal or out of bounds.
+fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
+    assert!(first_index != second_index);
+    let split_at_index = cmp::max(first_index, second_index);
+    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
+    if first_index < second_index {
+        (&mut first_slice[first_index], &mut second_slice[0])
+    } else {
+        (&mut second_slice[0], &mut first_slice[second_index])
+    }
+}
+
+fn ai_take_turn(
+    monster_id: usize,
+    map: &mut Map,
+    messages: &mut Messages,
+    objects: &mut [Object],
+    tcod: &Tcod,
+) {
+    use Ai::*;
+    // still making its way across slow ground
+    if objects[monster_id].move_delay > 0 {
+        objects[monster_id].move_delay -= 1;
+        return;
+    }
+    if let Some(ai) = objects[monster_id].ai.take() {
+        let new_ai = match ai {
+            Basic => ai_basic(monster_id, map, objects, messages, tcod),
+            Confused {
+                previous_ai,
+                num_turns,
+            } => ai_confused(
+                monster_id,
+                map,
+                objects,
+                messages,
+                previous_ai,
+                num_turns,
+                tcod,
+            ),
+            Alerted {
+                previous_ai,
+                target,
+                num_turns,
+            } => {
+                // give up after a while, in case the alarm can't be reached
+                if num_turns > 0 && ai_alerted(monster_id, target, map, objects, messages, tcod) {
+                    Alerted {
+                        previous_ai,
+                        target,
+                        num_turns: num_turns - 1,
+                    }
+                } else {
+                    *previous_ai
+                }
+            }
+            Merchant => Merchant,
+        };
+        // monsters can now die on their own turn, e.g. by walking into a trap
+        if objects[monster_id].alive {
+            objects[monster_id].ai = Some(new_ai)
+        }
+    }
+}
+
+fn ai_confused(
+    monster_id: usize,
+    map: &mut Map,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    previous_ai: Box<Ai>,
+    num_turns: i32,
+    tcod: &Tcod,
+) -> Ai {
+    if num_turns > 0 {
+        // move in a random idrection, and decrease the number of turns confused
+        move_by(
+            monster_id,
+            rand::thread_rng().gen_range(-1, 2),
+            rand::thread_rng().gen_range(-1, 2),
+            map,
+            objects,
+            messages,
+            tcod,
+        );
+        Ai::Confused {
+            previous_ai,
+            num_turns: num_turns - 1,
+        }
+    } else {
+        message(
+            messages,
+            format!("The {} is no longer confused!", objects[monster_id].name),
+            colors::RED,
+        );
+
+        *previous_ai
+    }
+}
+
+/// Walk towards the alarm at `target`. Returns false once the monster gets
+/// there or sees the player, so it can go back to what it was doing.
+fn ai_alerted(
+    monster_id: usize,
+    target: (i32, i32),
+    map: &mut Map,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    tcod: &Tcod,
+) -> bool {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let sees_player = can_see(&objects[monster_id], player_x, player_y, map);
+    if sees_player || objects[monster_id].pos() == target {
+        return false;
+    }
+    move_towards(monster_id, target.0, target.1, map, objects, messages, tcod);
+    true
+}
+
+fn ai_basic(
+    monster_id: usize,
+    map: &mut Map,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    tcod: &Tcod,
+) -> Ai {
+    // a basic monster takes its turn, if it can see the player
+    let (player_x, player_y) = objects[PLAYER].pos();
+    if can_see(&objects[monster_id], player_x, player_y, map) {
+        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+            // move towards player if far away
+            move_towards(monster_id, player_x, player_y, map, objects, messages, tcod);
+        } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
+            // close enough, attack! (if the player is still alive.)
+            let (monster, player) = mut_two(monster_id, PLAYER, objects);
+            monster.attack(messages, player);
+        }
+    }
+    Ai::Basic
+}
+
+fn main() {
+    let mut messages = vec![];
+
+    let mut tcod = Tcod {
+        config: Config::load(CONFIG_FILE),
+        root: Root::initializer()
+            .font("arial10x10.png", FontLayout::Tcod)
+            .font_type(FontType::Greyscale)
+            .size(SCREEN_WIDTH, SCREEN_HEIGHT)
+            .title("Rust/libtcod tutorial")
+            .init(),
+        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
+        sidebar: Offscreen::new(SIDEBAR_WIDTH, CAMERA_HEIGHT),
+        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        lightmap: vec![vec![colors::BLACK; MAP_HEIGHT as usize]; MAP_WIDTH as usize],
+        mouse: Default::default(),
+        camera: (0, 0),
+        floaters: vec![],
+    };
+
+    message(
+        &mut messages,
+        "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
+        colors::RED,
+    );
+
+    tcod::system::set_fps(LIMIT_FPS);
+
+    let mut player = Object::new(0, 0, '@', "player", colors::WHITE, true);
+    player.description = "That's you.".into();
+    player.alive = true;
+    player.gold = PLAYER_GOLD;
+    player.food = Some(PLAYER_FOOD);
+    player.fighter = Some(Fighter {
+        max_hp: 30,
+        hp: 30,
+        defense: 2,
+        power: 5,
+        sight_radius: PLAYER_SIGHT_RADIUS,
+        regen_turns: 5,
+        regen_progress: 0,
+        on_death: DeathCallback::Player,
+    });
+    // the player brings a lantern, for when the dungeon gets dark
+    let mut inventory: Vec<Object> = vec![make_lantern(0, 0)];
+    let mut objects = vec![player];
+    let mut dungeon_level = 1;
+    let mut discoveries = Discoveries::new();
+    let mut map = make_map(&mut objects, dungeon_level, &discoveries, &tcod.config);
+    let mut fov_recompute = true;
+    let mut occupation: Option<Occupation> = None;
+
+    initialise_fov(&map, &mut tcod);
+
+    while !tcod.root.window_closed() {
+        tcod.con.set_default_foreground(colors::WHITE);
+        tcod.root.clear();
+
+        let mut clicked = false;
+        let key = match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(m))) => {
+                tcod.mouse = m;
+                clicked = m.lbutton_pressed;
+                Default::default()
+            }
+            Some((_, Event::Key(k))) => k,
+            _ => Default::default(),
+        };
+
+        if fov_recompute {
+            tcod.lightmap = compute_lightmap(&map, &objects, &inventory, tcod.config.fov_algorithm);
+        }
+        render_all(&mut tcod, &objects, &map, &messages, fov_recompute);
+        remember_surroundings(&mut objects, &mut map, &tcod);
+        tcod.root.flush();
+        for object in &objects {
+            object.clear(&mut tcod.con)
+        }
+        let level_before = dungeon_level;
+        let position_before = objects[PLAYER].pos();
+
+        // any key press interrupts what the player is busy with
+        if let Some(ref current) = occupation {
+            if key.code != NoKey {
+                message(
+                    &mut messages,
+                    format!("You stop {}.", current.activity.verb()),
+                    colors::WHITE,
+                );
+                occupation = None;
+            }
+        }
+
+        let player_action = if let Some(current) = occupation.take() {
+            let (next, action) =
+                continue_occupation(current, &mut map, &mut objects, &mut messages, &tcod);
+            occupation = next;
+            action
+        } else if clicked && objects[PLAYER].alive {
+            // don't let the click leak into the next targeting prompt
+            tcod.mouse.lbutton_pressed = false;
+            if let Some(target) = tcod.mouse_map_pos() {
+                occupation = start_travel(target, &map, &objects, &mut messages);
+            }
+            DidntTakeTurn
+        } else if key.printable == '_' && objects[PLAYER].alive {
+            occupation = travel_to_feature(&mut tcod, &map, &objects, &mut messages);
+            DidntTakeTurn
+        } else if key.printable == 'R' && objects[PLAYER].alive {
+            occupation = start_rest(&objects, &mut messages);
+            DidntTakeTurn
+        } else {
+            handle_keys(
+                key,
+                &mut tcod,
+                &mut map,
+                &mut objects,
+                &mut inventory,
+                &mut messages,
+                &mut dungeon_level,
+                &mut discoveries,
+            )
+        };
+        if player_action == PlayerAction::Exit {
+            break;
+        }
+        // the player may have moved or opened a door, and on a new level
+        // everything changed
+        fov_recompute = player_action != DidntTakeTurn || dungeon_level != level_before;
+
+        if objects[PLAYER].alive && player_action != DidntTakeTurn {
+            spot_traps(&mut objects, &mut messages, PASSIVE_SPOT_CHANCE);
+        }
+
+        // walking onto slow terrain gives the monsters extra turns
+        let mut turns = 1;
+        let (player_x, player_y) = objects[PLAYER].pos();
+        if player_action != DidntTakeTurn && (player_x, player_y) != position_before {
+            turns = map[player_x as usize][player_y as usize].kind.move_cost();
+            // a heavy pack makes every step take longer
+            if inventory_weight(&inventory) > carry_capacity(&objects[PLAYER]) {
+                turns += 1;
+            }
+            enter_tile(PLAYER, &map, &mut objects, &mut inventory, &mut messages);
+        }
+
+        if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
+            for _ in 0..turns {
+                for id in 0..objects.len() {
+                    if objects[id].ai.is_some() {
+                        let monster_position = objects[id].pos();
+                        ai_take_turn(id, &mut map, &mut messages, &mut objects, &tcod);
+                        if objects[id].pos() != monster_position {
+                            enter_tile(id, &map, &mut objects, &mut inventory, &mut messages);
+                        }
+                    }
+                }
+                // nothing more happens to the player once they're dead
+                if !objects[PLAYER].alive {
+                    break;
+                }
+                for object in &mut objects {
+                    object.regenerate();
+                }
+                burn_fuel(&mut inventory, &mut messages);
+                rot_corpses(&mut objects, &mut inventory);
+                digest(&mut objects[PLAYER], &mut messages);
+                if !objects[PLAYER].alive {
+                    break;
+                }
+            }
+        }
+        drop_loot(
+            &mut objects,
+            &map,
+            &mut messages,
+            &discoveries,
+            dungeon_level,
+        );
+        show_damage(&mut objects, &mut tcod);
+    }
+}
+
+/// Float the damage everything in view took this turn over its head.
+fn show_damage(objects: &mut [Object], tcod: &mut Tcod) {
+    for (id, object) in objects.iter_mut().enumerate() {
+        if object.recent_damage > 0 && tcod.is_visible(object.x, object.y) {
+            let color = if id == PLAYER {
+                colors::LIGHT_RED
+            } else {
+                colors::YELLOW
+            };
+            tcod.floaters.push(Floater {
+                x: object.x,
+                y: object.y - 1,
+                text: format!("-{}", object.recent_damage),
+                color,
+                frames_left: FLOATER_FRAMES,
+            });
+        }
+        object.recent_damage = 0;
+    }
+}
+
+/// Green for healthy, fading to red as the hit points run out.
+fn health_color(hp: i32, max_hp: i32) -> Color {
+    let fraction = cmp::max(hp, 0) as f32 / max_hp as f32;
+    colors::lerp(colors::DARK_RED, colors::DARK_GREEN, fraction)
+}
+
+fn get_names_under_mouse(tcod: &Tcod, objects: &[Object]) -> String {
+    let (x, y) = match tcod.mouse_map_pos() {
+        Some(pos) => pos,
+        None => return String::new(),
     };
 
+    let names = objects
+        .iter()
+        .filter(|obj| obj.pos() == (x, y) && tcod.is_visible(obj.x, obj.y) && !obj.is_hidden())
+        .map(|obj| obj.name.clone())
+        .collect::<Vec<_>>();
+
+    names.join(", ")
+}
+
+impl Tcod {
+    /// Whether the player can see the tile: it has to be in view, and lit.
+    pub fn is_visible(&self, x: i32, y: i32) -> bool {
+        self.fov.is_in_fov(x, y)
+            && light_intensity(self.lightmap[x as usize][y as usize]) >= MIN_VISIBLE_LIGHT
+    }
+
+    /// How much of the map fits across the screen, next to the side panel
+    /// if there is one.
+    pub fn camera_width(&self) -> i32 {
+        if self.config.side_panel {
+            SCREEN_WIDTH - SIDEBAR_WIDTH
+        } else {
+            SCREEN_WIDTH
+        }
+    }
+
+    /// Center the camera on the given map position, without scrolling past
+    /// the edges of the map.
+    pub fn move_camera(&mut self, target_x: i32, target_y: i32) {
+        let width = self.camera_width();
+        let x = (target_x - width / 2).clamp(0, MAP_WIDTH - width);
+        let y = (target_y - CAMERA_HEIGHT / 2).clamp(0, MAP_HEIGHT - CAMERA_HEIGHT);
+        self.camera = (x, y);
+    }
+
+    /// The map position under the mouse, or `None` when it's not over the map.
+    pub fn mouse_map_pos(&self) -> Option<(i32, i32)> {
+        let (x, y) = (self.mouse.cx as i32, self.mouse.cy as i32);
+        if x < 0 || y < 0 || x >= self.camera_width() || y >= CAMERA_HEIGHT {
+            return None;
+        }
+        Some((x + self.camera.0, y + self.camera.1))
+    }
+
+    pub fn target_tile(
+        self: &mut Tcod,
+        objects: &[Object],
+        map: &mut Map,
+        messages: &Messages,
+        max_range: Option<f32>,
+        blast_radius: Option<i32>,
+    ) -> Option<(i32, i32)> {
+        use tcod::input::KeyCode::Escape;
+        loop {
+            self.root.flush();
+
+            let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
+            let mut key = None;
+            match event {
+                Some(Event::Mouse(m)) => self.mouse = m,
+                Some(Event::Key(k)) => key = Some(k),
+                None => {}
+            }
+            render_all(self, objects, map, messages, false);
+
+            // accept the target if the player clicked in FOV, and in case a range
+            // is specified, if it's in that range
+            let target = self
+                .mouse_map_pos()
+                .filter(|&(x, y)| self.can_target(objects, x, y, max_range));
+            self.show_aim(objects, target, max_range, blast_radius);
+            if let Some(target) = target {
+                if self.mouse.lbutton_pressed {
+                    return Some(target);
+                }
+            }
+
+            let escape = key.map_or(false, |k| k.code == Escape);
+            if self.mouse.rbutton_pressed || escape {
+                return None; // cancel if the player right-clicked or pressed Escape
+            }
+        }
+    }
+
+    fn can_target(&self, objects: &[Object], x: i32, y: i32, max_range: Option<f32>) -> bool {
+        let in_range = max_range.map(|range| objects[PLAYER].distance(x, y) <= range);
+        self.is_visible(x, y) && in_range != Some(false)
+    }
+
+    /// Highlight the tiles in range and, around the target, the tiles and
+    /// creatures the blast would hit.
+    fn show_aim(
+        &mut self,
+        objects: &[Object],
+        target: Option<(i32, i32)>,
+        max_range: Option<f32>,
+        blast_radius: Option<i32>,
+    ) {
+        let (camera_x, camera_y) = self.camera;
+        let in_blast = |x: i32, y: i32| match (target, blast_radius) {
+            (Some((target_x, target_y)), Some(radius)) => {
+                let distance = (((x - target_x).pow(2) + (y - target_y).pow(2)) as f32).sqrt();
+                distance <= radius as f32
+            }
+            _ => false,
+        };
+        for screen_x in 0..self.camera_width() {
+            for screen_y in 0..CAMERA_HEIGHT {
+                let (x, y) = (screen_x + camera_x, screen_y + camera_y);
+                let highlight = if in_blast(x, y) {
+                    colors::DARKER_ORANGE
+                } else if max_range.is_some() && self.can_target(objects, x, y, max_range) {
+                    colors::DARKEST_AZURE
+                } else {
+                    continue;
+                };
+                self.root
+                    .set_char_background(screen_x, screen_y, highlight, BackgroundFlag::Add);
+            }
+        }
+        // fireballs don't care whose side anyone is on, so this includes the player
+        for object in objects {
+            let (screen_x, screen_y) = (object.x - camera_x, object.y - camera_y);
+            let on_screen = screen_x >= 0
+                && screen_y >= 0
+                && screen_x < self.camera_width()
+                && screen_y < CAMERA_HEIGHT;
+            let seen = self.is_visible(object.x, object.y);
+            if on_screen && seen && object.fighter.is_some() && in_blast(object.x, object.y) {
+                self.root
+                    .set_char_background(screen_x, screen_y, colors::RED, BackgroundFlag::Set);
+            }
+        }
+    }
+
+    fn target_monster(
+        self: &mut Tcod,
+        objects: &[Object],
+        map: &mut Map,
+        messages: &Messages,
+        max_range: Option<f32>,
+    ) -> Option<usize> {
+        loop {
+            match self.target_tile(objects, map, messages, max_range, None) {
+                Some((x, y)) => {
+                    // return the first clicked monster, otherwise continue looping
+                    for (id, obj) in objects.iter().enumerate() {
+                        if obj.pos() == (x, y) && obj.fighter.is_some() && id != PLAYER {
+                            return Some(id);
+                        }
+                    }
+                }
+                None => return None,
+            }
+        }
+    }
+
+    /// Let the player pick one of the options with a letter. Options past
+    /// the 26th go on further pages, turned with `<` and `>`.
+    pub fn menu<T: AsRef<str>>(
+        self: &mut Tcod,
+        header: &str,
+        options: &[T],
+        width: i32,
+    ) -> Option<usize> {
+        let lines: Vec<MenuLine> = options
+            .iter()
+            .map(|option| MenuLine::Option(option.as_ref().into()))
+            .collect();
+        match self.menu_lines(header, &lines, width, &[]) {
+            MenuResult::Chose(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// A menu that can also have headings between its options, and keys
+    /// other than the option letters that close it, reported back as
+    /// `MenuResult::Pressed`. Options are numbered leaving the headings out.
+    fn menu_lines(
+        self: &mut Tcod,
+        header: &str,
+        lines: &[MenuLine],
+        width: i32,
+        keys: &[char],
+    ) -> MenuResult {
+        // split the lines into pages of up to 26 options each, along with the
+        // number of the first option on every page
+        let mut pages: Vec<(usize, &[MenuLine])> = vec![];
+        let (mut page_start, mut first, mut count) = (0, 0, 0);
+        for (index, line) in lines.iter().enumerate() {
+            if let MenuLine::Option(_) = line {
+                if count == MENU_PAGE_SIZE {
+                    pages.push((first, &lines[page_start..index]));
+                    page_start = index;
+                    first += count;
+                    count = 0;
+                }
+                count += 1;
+            }
+        }
+        pages.push((first, &lines[page_start..]));
+        let mut page = 0;
+
+        // calculate total height for the header (after auto-wrap), one line per
+        // option or heading and a line for the page number, if there's more
+        // than one page
+        let header_height = self
+            .root
+            .get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
+        let footer_height = if pages.len() > 1 { 1 } else { 0 };
+        let longest = pages
+            .iter()
+            .map(|(_, lines)| lines.len())
+            .max()
+            .unwrap_or(0);
+        let height = longest as i32 + header_height + footer_height;
+
+        // keep what's under the menu, to draw the next page over
+        let mut backdrop = Offscreen::new(SCREEN_WIDTH, SCREEN_HEIGHT);
+        blit(
+            &self.root,
+            (0, 0),
+            (SCREEN_WIDTH, SCREEN_HEIGHT),
+            &mut backdrop,
+            (0, 0),
+            1.0,
+            1.0,
+        );
+
+        let result = loop {
+            // create an off-screen console that represents the menu's window
+            let mut window = Offscreen::new(width, height);
+
+            // print the header, with auto-wrap
+            window.set_default_foreground(colors::WHITE);
+            window.print_rect_ex(
+                0,
+                0,
+                width,
+                height,
+                BackgroundFlag::None,
+                TextAlignment::Left,
+                header,
+            );
+
+            let (first, shown) = pages[page];
+            let mut options = 0;
+            for (index, line) in shown.iter().enumerate() {
+                let text = match line {
+                    MenuLine::Option(text) => {
+                        let menu_letter = (b'a' + options as u8) as char;
+                        options += 1;
+                        window.set_default_foreground(colors::WHITE);
+                        format!("({}) {}", menu_letter, text)
+                    }
+                    MenuLine::Heading(text) => {
+                        window.set_default_foreground(colors::LIGHT_YELLOW);
+                        text.clone()
+                    }
+                };
+                window.print_ex(
+                    0,
+                    header_height + index as i32,
+                    BackgroundFlag::None,
+                    TextAlignment::Left,
+                    text,
+                );
+            }
+            if pages.len() > 1 {
+                window.set_default_foreground(colors::WHITE);
+                window.print_ex(
+                    width / 2,
+                    height - 1,
+                    BackgroundFlag::None,
+                    TextAlignment::Center,
+                    format!("< page {} of {} >", page + 1, pages.len()),
+                );
+            }
+
+            // blit the contents of "window" to the root console
+            let x = SCREEN_WIDTH / 2 - width / 2;
+            let y = SCREEN_HEIGHT / 2 - height / 2;
+            blit(
+                &backdrop,
+                (0, 0),
+                (SCREEN_WIDTH, SCREEN_HEIGHT),
+                &mut self.root,
+                (0, 0),
+                1.0,
+                1.0,
+            );
+            tcod::console::blit(
+                &window,
+                (0, 0),
+                (width, height),
+                &mut self.root,
+                (x, y),
+                1.0,
+                0.7,
+            );
+
+            // present the root console to the player and wait for a key-press
+            self.root.flush();
+            let key = self.root.wait_for_keypress(true);
+
+            match key.printable {
+                '>' if page + 1 < pages.len() => page += 1,
+                '<' if page > 0 => page -= 1,
+                key if keys.contains(&key) => break MenuResult::Pressed(key),
+                // convert the ASCII code to an index; if it corresponds to an
+                // option, return it
+                letter if letter.is_ascii_alphabetic() => {
+                    let index = letter.to_ascii_lowercase() as usize - 'a' as usize;
+                    break if index < options {
+                        MenuResult::Chose(first + index)
+                    } else {
+                        MenuResult::Cancelled
+                    };
+                }
+                _ => break MenuResult::Cancelled,
+            }
+        };
+
+        // leave the screen as it was, in case another menu opens right away
+        blit(
+            &backdrop,
+            (0, 0),
+            (SCREEN_WIDTH, SCREEN_HEIGHT),
+            &mut self.root,
+            (0, 0),
+            1.0,
+            1.0,
+        );
+        result
+    }
+
+    /// Show the inventory in the order set by `inventory_sort`, which the
+    /// player can change with `=` while the menu is open. Returns the index
+    /// in `inventory` of the item picked.
+    pub fn inventory_menu(
+        self: &mut Tcod,
+        inventory: &[Object],
+        header: &str,
+        capacity: i32,
+    ) -> Option<usize> {
+        let weight = inventory_weight(inventory);
+        let burden = if weight > capacity { " (burdened)" } else { "" };
+
+        loop {
+            let sort = self.config.inventory_sort;
+            let header = format!(
+                "{}Carrying {} of {} weight{}. Sorted by {}, press = to change.\n",
+                header,
+                weight,
+                capacity,
+                burden,
+                sort.name()
+            );
+
+            // list the items in the order the player asked for, keeping track of
+            // where each one really is in the inventory
+            let mut order: Vec<usize> = (0..inventory.len()).collect();
+            match sort {
+                InventorySort::Category => order.sort_by_key(|&id| {
+                    (
+                        inventory[id].item.map(|item| item.category()),
+                        inventory[id].name.clone(),
+                    )
+                }),
+                InventorySort::Name => order.sort_by_key(|&id| inventory[id].name.clone()),
+                InventorySort::Pickup => {}
+            }
+
+            let mut lines = vec![];
+            if inventory.is_empty() {
+                lines.push(MenuLine::Heading("Inventory is empty.".into()));
+            }
+            let mut last_category = None;
+            for &id in &order {
+                let category = inventory[id].item.map(|item| item.category());
+                // a heading above each group of items
+                if sort == InventorySort::Category
+                    && (lines.is_empty() || category != last_category)
+                {
+                    let heading = category.map_or("Other", |category| category.heading());
+                    lines.push(MenuLine::Heading(heading.into()));
+                    last_category = category;
+                }
+                lines.push(MenuLine::Option(inventory[id].display_name()));
+            }
+
+            match self.menu_lines(&header, &lines, INVENTORY_WIDTH, &['=']) {
+                MenuResult::Chose(index) => return Some(order[index]),
+                MenuResult::Pressed('=') => self.config.inventory_sort = sort.next(),
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn use_item(
+    inventory_id: usize,
+    inventory: &mut Vec<Object>,
+    objects: &mut [Object],
+    messages: &mut Messages,
+    map: &mut Map,
+    tcod: &mut Tcod,
+    discoveries: &mut Discoveries,
+) {
+    use Item::*;
+
+    if let Some(item) = inventory[inventory_id].item {
+        let result = match item {
+            Heal => cast_heal(inventory_id, objects, messages, map, tcod),
+            Lightning => cast_lightning(inventory_id, objects, messages, map, tcod),
+            Confuse => cast_confuse(inventory_id, objects, messages, map, tcod),
+            Fireball => cast_fireball(inventory_id, objects, messages, map, tcod),
+            Identify => cast_identify(inventory, objects, messages, tcod, discoveries),
+            Ration | Corpse => eat(&inventory[inventory_id], objects, messages),
+            Lantern => {
+                toggle_lantern(&mut inventory[inventory_id], messages);
+                return;
+            }
+        };
+
+        match result {
+            UseResult::UsedUp => {
+                // once it has done something, the player finds out what it is
+                discoveries.identify(item, objects, inventory, messages);
+                take_one(inventory, inventory_id);
+            }
+            UseResult::Cancelled => {
+                message(messages, "Cancelled", colors::WHITE);
+            }
+        }
+    } else {
+        message(
+            messages,
+            format!("The {} cannot be used.", inventory[inventory_id].name),
+            colors::WHITE,
+        );
+    }
+}
+
+/// Advance to the next level
+fn next_level(
+    tcod: &mut Tcod,
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    messages: &mut Messages,
+    dungeon_level: &mut u32,
+    discoveries: &Discoveries,
+) {
     message(
-        &mut messages,
-        "Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
+        messages,
+        "You descend deeper into the heart of the dungeon...",
         colors::RED,
     );
+    *dungeon_level += 1;
+    // everything but the player stays behind
+    objects.truncate(1);
+    *map = make_map(objects, *dungeon_level, discoveries, &tcod.config);
+    initialise_fov(map, tcod);
+}
 
-    tcod::system::set_fps(LIMIT_FPS);
-
-    let mut player = Object::new(0, 0, '@', "player", colors::WHITE, true);
-    player.alive = true;
-    player.fighter = Some(Fighter {
-        max_hp: 30,
-        hp: 30,
-        defense: 2,
-        power: 5,
-        on_death: DeathCallback::Player,
-    });
-    let mut inventory: Vec<Object> = vec![];
-    let mut objects = vec![player];
-    let mut map = make_map(&mut objects);
-    let mut previous_player_position = (-1, -1);
-    let mut key = Default::default();
+fn initialise_fov(map: &Map, tcod: &mut Tcod) {
+    update_fov_map(map, &mut tcod.fov);
+    // unexplored areas start black (which is the default background color)
+    tcod.con.clear();
+}
 
+/// Copy which tiles block sight and movement over to the FOV map, e.g. after
+/// doors have been opened or closed.
+fn update_fov_map(map: &Map, fov: &mut FovMap) {
     for y in 0..MAP_HEIGHT {
         for x in 0..MAP_WIDTH {
-            tcod.fov.set(
+            fov.set(
                 x,
                 y,
                 !map[x as usize][y as usize].block_sight,
@@ -866,414 +4161,842 @@ fn main() {
             );
         }
     }
+}
 
-    while !tcod.root.window_closed() {
-        tcod.con.set_default_foreground(colors::WHITE);
-        tcod.root.clear();
-
-        let fov_recompute = previous_player_position != (objects[0].x, objects[0].y);
-
-        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
-            Some((_, Event::Mouse(m))) => tcod.mouse = m,
-            Some((_, Event::Key(k))) => key = k,
-            _ => key = Default::default(),
-        };
-
-        render_all(&mut tcod, &objects, &mut map, &messages, fov_recompute);
-        tcod.root.flush();
-        for object in &objects {
-            object.clear(&mut tcod.con)
+#[allow(clippy::too_many_arguments)]
+fn handle_keys(
+    key: Key,
+    tcod: &mut Tcod,
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+    dungeon_level: &mut u32,
+    discoveries: &mut Discoveries,
+) -> PlayerAction {
+    let player_alive = objects[PLAYER].alive;
+    match (key, player_alive) {
+        (Key { code: Up, .. }, true) => {
+            if player_move_or_attack(0, -1, map, messages, objects, tcod) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
         }
-        let player = &mut objects[0];
-
-        previous_player_position = (player.x, player.y);
-        let player_action = handle_keys(
-            key,
-            &mut tcod,
-            &mut map,
-            &mut objects,
-            &mut inventory,
-            &mut messages,
-        );
-        if player_action == PlayerAction::Exit {
-            break;
+        (Key { code: Down, .. }, true) => {
+            if player_move_or_attack(0, 1, map, messages, objects, tcod) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
         }
-
-        if objects[PLAYER].alive && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(id, &map, &mut messages, &mut objects, &tcod);
-                }
+        (Key { code: Left, .. }, true) => {
+            if player_move_or_attack(-1, 0, map, messages, objects, tcod) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+        (Key { code: Right, .. }, true) => {
+            if player_move_or_attack(1, 0, map, messages, objects, tcod) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+        (Key { printable: 'i', .. }, true) => {
+            let inventory_index = tcod.inventory_menu(
+                inventory,
+                "Press the key next to an item to use it, or any other to cancel.\n",
+                carry_capacity(&objects[PLAYER]),
+            );
+            if let Some(inventory_index) = inventory_index {
+                use_item(
+                    inventory_index,
+                    inventory,
+                    objects,
+                    messages,
+                    map,
+                    tcod,
+                    discoveries,
+                );
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+        (Key { printable: 'g', .. }, true) => {
+            let item_id = objects.iter().position(|object| {
+                let takeable = object.item.is_some() || (object.gold > 0 && !object.alive);
+                object.pos() == objects[PLAYER].pos() && takeable
+            });
+            if let Some(item_id) = item_id {
+                pick_item_up(item_id, objects, inventory, messages);
+            }
+            DidntTakeTurn
+        }
+        (Key { printable: 't', .. }, true) => {
+            let merchant_id = objects.iter().position(|object| {
+                object.ai == Some(Ai::Merchant) && object.distance_to(&objects[PLAYER]) < 2.0
+            });
+            match merchant_id {
+                Some(merchant_id) => trade(merchant_id, objects, inventory, messages, tcod),
+                None => message(
+                    messages,
+                    "There's nobody here to trade with.",
+                    colors::WHITE,
+                ),
+            }
+            DidntTakeTurn
+        }
+        (Key { printable: 'c', .. }, true) => {
+            if close_door(map, objects, messages) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+        (Key { printable: 's', .. }, true) => {
+            search(map, objects, messages);
+            TookTurn
+        }
+        (Key { printable: 'D', .. }, true) => {
+            if disarm_trap(map, objects, messages, tcod) {
+                TookTurn
+            } else {
+                DidntTakeTurn
+            }
+        }
+        (Key { printable: 'l', .. }, _) => {
+            look(tcod, objects, map, messages, discoveries);
+            DidntTakeTurn
+        }
+        (
+            Key {
+                printable: '\\', ..
+            },
+            _,
+        ) => {
+            show_discoveries(tcod, discoveries);
+            DidntTakeTurn
+        }
+        (Key { printable: 'm', .. }, _) => {
+            show_overview(tcod, map, objects, *dungeon_level);
+            DidntTakeTurn
+        }
+        (Key { printable: '>', .. }, true) => {
+            // go down stairs, if the player is on them
+            let player_on_stairs = objects.iter().any(|object| {
+                object.pos() == objects[PLAYER].pos() && object.feature == Some(Feature::Stairs)
+            });
+            if player_on_stairs {
+                next_level(tcod, map, objects, messages, dungeon_level, discoveries);
             }
+            DidntTakeTurn
+        }
+        (
+            Key {
+                code: Enter,
+                alt: true,
+                ..
+            },
+            _,
+        ) => {
+            let fullscreen = tcod.root.is_fullscreen();
+            tcod.root.set_fullscreen(!fullscreen);
+            DidntTakeTurn
         }
+        (Key { code: Escape, .. }, _) => Exit,
+        _ => DidntTakeTurn,
     }
 }
 
-fn get_names_under_mouse(tcod: &Tcod, objects: &[Object]) -> String {
-    let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+/// Buy and sell items with the merchant, until the player is done.
+fn trade(
+    merchant_id: usize,
+    objects: &mut [Object],
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+    tcod: &mut Tcod,
+) {
+    loop {
+        let header = format!(
+            "You have {} gold, the {} has {}.\n",
+            objects[PLAYER].gold, objects[merchant_id].name, objects[merchant_id].gold
+        );
+        match tcod.menu(&header, &["Buy", "Sell"], INVENTORY_WIDTH) {
+            Some(0) => buy(merchant_id, objects, inventory, messages, tcod),
+            Some(_) => sell(merchant_id, objects, inventory, messages, tcod),
+            None => return,
+        }
+    }
+}
 
-    let names = objects
+fn buy(
+    merchant_id: usize,
+    objects: &mut [Object],
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+    tcod: &mut Tcod,
+) {
+    let options: Vec<String> = objects[merchant_id]
+        .wares
         .iter()
-        .filter(|obj| obj.pos() == (x, y) && tcod.fov.is_in_fov(obj.x, obj.y))
-        .map(|obj| obj.name.clone())
-        .collect::<Vec<_>>();
+        .map(|ware| {
+            let price = ware.item.map_or(0, |item| item.price());
+            format!("{} - {} gold", ware.display_name(), price)
+        })
+        .collect();
+    if options.is_empty() {
+        message(messages, "There's nothing left to buy.", colors::WHITE);
+        return;
+    }
+    let choice = match tcod.menu(
+        "Press the key next to an item to buy it, or any other to cancel.\n",
+        &options,
+        INVENTORY_WIDTH,
+    ) {
+        Some(choice) => choice,
+        None => return,
+    };
 
-    names.join(", ")
+    let ware = &objects[merchant_id].wares[choice];
+    let price = ware.item.map_or(0, |item| item.price());
+    let weight = ware.item.map_or(0, |item| item.weight());
+    let max_load = carry_capacity(&objects[PLAYER]) * MAX_LOAD_PERCENT / 100;
+    if objects[PLAYER].gold < price {
+        message(
+            messages,
+            format!("You can't afford the {}.", ware.name),
+            colors::RED,
+        );
+    } else if inventory_weight(inventory) + weight > max_load {
+        message(
+            messages,
+            format!("You can't carry the {} as well.", ware.name),
+            colors::RED,
+        );
+    } else {
+        let item = take_one(&mut objects[merchant_id].wares, choice);
+        message(
+            messages,
+            format!("You buy the {} for {} gold.", item.name, price),
+            colors::GOLD,
+        );
+        objects[PLAYER].gold -= price;
+        objects[merchant_id].gold += price;
+        add_item(inventory, item);
+    }
 }
 
-impl Tcod {
-    pub fn target_tile(
-        self: &mut Tcod,
-        objects: &[Object],
-        map: &mut Map,
-        messages: &Messages,
-        max_range: Option<f32>,
-    ) -> Option<(i32, i32)> {
-        use tcod::input::KeyCode::Escape;
-        loop {
-            self.root.flush();
-
-            let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
-            let mut key = None;
-            match event {
-                Some(Event::Mouse(m)) => self.mouse = m,
-                Some(Event::Key(k)) => key = Some(k),
-                None => {}
-            }
-            render_all(self, objects, map, messages, false);
-
-            let (x, y) = (self.mouse.cx as i32, self.mouse.cy as i32);
+fn sell(
+    merchant_id: usize,
+    objects: &mut [Object],
+    inventory: &mut Vec<Object>,
+    messages: &mut Messages,
+    tcod: &mut Tcod,
+) {
+    // merchants only pay part of what they would sell it for
+    let sell_price = |item: &Object| item.item.map_or(0, |item| item.price()) * SELL_PERCENT / 100;
+    let options: Vec<String> = inventory
+        .iter()
+        .map(|item| format!("{} - {} gold", item.display_name(), sell_price(item)))
+        .collect();
+    if options.is_empty() {
+        message(messages, "You have nothing to sell.", colors::WHITE);
+        return;
+    }
+    let choice = match tcod.menu(
+        "Press the key next to an item to sell it, or any other to cancel.\n",
+        &options,
+        INVENTORY_WIDTH,
+    ) {
+        Some(choice) => choice,
+        None => return,
+    };
 
-            // accept the target if the player clicked in FOV, and in case a range
-            // is specified, if it's in that range
-            let in_fov = (x < MAP_WIDTH) && (y < MAP_HEIGHT) && self.fov.is_in_fov(x, y);
-            let in_range = max_range.map_or(true, |range| objects[PLAYER].distance(x, y) <= range);
-            if self.mouse.lbutton_pressed && in_fov && in_range {
-                return Some((x, y));
-            }
+    let price = sell_price(&inventory[choice]);
+    if objects[merchant_id].gold < price {
+        message(
+            messages,
+            format!(
+                "The {} can't afford your {}.",
+                objects[merchant_id].name, inventory[choice].name
+            ),
+            colors::RED,
+        );
+    } else {
+        let item = take_one(inventory, choice);
+        message(
+            messages,
+            format!("You sell the {} for {} gold.", item.name, price),
+            colors::GOLD,
+        );
+        objects[PLAYER].gold += price;
+        objects[merchant_id].gold -= price;
+        add_item(&mut objects[merchant_id].wares, item);
+    }
+}
 
-            let escape = key.map_or(false, |k| k.code == Escape);
-            if self.mouse.rbutton_pressed || escape {
-                return None; // cancel if the player right-clicked or pressed Escape
-            }
-        }
+/// List the kinds of items the player has identified, and what they looked
+/// like before.
+fn show_discoveries(tcod: &mut Tcod, discoveries: &Discoveries) {
+    let mut discovered: Vec<String> = discoveries
+        .identified
+        .iter()
+        .filter_map(|&item| {
+            discoveries
+                .appearance(item)
+                .map(|appearance| format!("{}: {}", appearance, item.name()))
+        })
+        .collect();
+    if discovered.is_empty() {
+        discovered.push("nothing yet".into());
     }
+    tcod.menu("Discovered items:\n", &discovered, INVENTORY_WIDTH);
+}
 
-    fn target_monster(
-        self: &mut Tcod,
-        objects: &[Object],
-        map: &mut Map,
-        messages: &Messages,
-        max_range: Option<f32>,
-    ) -> Option<usize> {
-        loop {
-            match self.target_tile(objects, map, messages, max_range) {
-                Some((x, y)) => {
-                    // return the first clicked monster, otherwise continue looping
-                    for (id, obj) in objects.iter().enumerate() {
-                        if obj.pos() == (x, y) && obj.fighter.is_some() && id != PLAYER {
-                            return Some(id);
-                        }
-                    }
+/// Let the player move a cursor over the map with the arrow keys or the
+/// mouse, describing everything under it, until they press Escape or Enter.
+fn look(
+    tcod: &mut Tcod,
+    objects: &[Object],
+    map: &mut Map,
+    messages: &Messages,
+    discoveries: &Discoveries,
+) {
+    let mut cursor = objects[PLAYER].pos();
+    loop {
+        let event = input::check_for_event(input::KEY_PRESS | input::MOUSE).map(|e| e.1);
+        let (dx, dy) = match event {
+            Some(Event::Mouse(m)) => {
+                tcod.mouse = m;
+                if let Some(pos) = tcod.mouse_map_pos() {
+                    cursor = pos;
                 }
-                None => return None,
+                if m.rbutton_pressed {
+                    return;
+                }
+                (0, 0)
             }
-        }
-    }
+            Some(Event::Key(key)) => match key.code {
+                Up => (0, -1),
+                Down => (0, 1),
+                Left => (-1, 0),
+                Right => (1, 0),
+                Escape | Enter => return,
+                _ => (0, 0),
+            },
+            None => (0, 0),
+        };
+        render_all(tcod, objects, map, messages, false);
 
-    pub fn menu<T: AsRef<str>>(
-        self: &mut Tcod,
-        header: &str,
-        options: &[T],
-        width: i32,
-    ) -> Option<usize> {
-        assert!(
-            options.len() <= 26,
-            "Cannot have a menu with more than 26 options"
+        // keep the cursor on the part of the map that's on screen
+        let (camera_x, camera_y) = tcod.camera;
+        cursor = (
+            (cursor.0 + dx).clamp(camera_x, camera_x + tcod.camera_width() - 1),
+            (cursor.1 + dy).clamp(camera_y, camera_y + CAMERA_HEIGHT - 1),
         );
+        let (screen_x, screen_y) = (cursor.0 - camera_x, cursor.1 - camera_y);
+        tcod.root
+            .set_char_background(screen_x, screen_y, colors::LIGHT_GREY, BackgroundFlag::Set);
 
-        // calculate total height for the header (after auto-wrap) and one line per option
-        let header_height = self
+        // show the description on the other side of the screen from the cursor
+        let text = describe_tile(cursor.0, cursor.1, objects, map, tcod, discoveries);
+        let height = tcod
             .root
-            .get_height_rect(0, 0, width, SCREEN_HEIGHT, header);
-        let height = options.len() as i32 + header_height;
-
-        // create an off-screen console that represents the menu's window
-        let mut window = Offscreen::new(width, height);
-
-        // print the header, with auto-wrap
+            .get_height_rect(0, 0, LOOK_WIDTH, SCREEN_HEIGHT, &text);
+        let mut window = Offscreen::new(LOOK_WIDTH, height);
         window.set_default_foreground(colors::WHITE);
-        window.print_rect_ex(
-            0,
-            0,
-            width,
-            height,
-            BackgroundFlag::None,
-            TextAlignment::Left,
-            header,
-        );
-
-        for (index, option_text) in options.iter().enumerate() {
-            let menu_letter = (b'a' + index as u8) as char;
-            let text = format!("({}) {}", menu_letter, option_text.as_ref());
-            window.print_ex(
-                0,
-                header_height + index as i32,
-                BackgroundFlag::None,
-                TextAlignment::Left,
-                text,
-            );
-        }
-
-        // blit the contents of "window" to the root console
-        let x = SCREEN_WIDTH / 2 - width / 2;
-        let y = SCREEN_HEIGHT / 2 - height / 2;
-        tcod::console::blit(
+        window.print_rect(0, 0, LOOK_WIDTH, height, &text);
+        let x = if screen_x < tcod.camera_width() / 2 {
+            SCREEN_WIDTH - LOOK_WIDTH - 1
+        } else {
+            1
+        };
+        blit(
             &window,
             (0, 0),
-            (width, height),
-            &mut self.root,
-            (x, y),
+            (LOOK_WIDTH, height),
+            &mut tcod.root,
+            (x, 1),
             1.0,
             0.7,
         );
+        tcod.root.flush();
+    }
+}
 
-        // present the root console to the player and wait for a key-press
-        self.root.flush();
-        let key = self.root.wait_for_keypress(true);
+/// Everything the player knows about the tile at (x, y) and what's on it.
+fn describe_tile(
+    x: i32,
+    y: i32,
+    objects: &[Object],
+    map: &Map,
+    tcod: &Tcod,
+    discoveries: &Discoveries,
+) -> String {
+    let tile = &map[x as usize][y as usize];
+    if !tile.explored {
+        return "You haven't been here yet.".into();
+    }
+    let terrain = match tile.door {
+        Some(Door::Open) => "an open door",
+        Some(Door::Closed) => "a closed door",
+        // secret doors look just like walls
+        Some(Door::Secret) => TileKind::Wall.name(),
+        None => tile.kind.name(),
+    };
+    let mut lines = vec![format!("Terrain: {}", terrain)];
+    if !tcod.is_visible(x, y) {
+        lines.push("You can't see what's there from here.".into());
+        return lines.join("\n");
+    }
 
-        // convert the ASCII code to an index; if it corresponds to an option, return it
-        if key.printable.is_alphabetic() {
-            let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
-            if index < options.len() {
-                Some(index)
+    let player = &objects[PLAYER];
+    for object in objects
+        .iter()
+        .filter(|o| o.pos() == (x, y) && !o.is_hidden())
+    {
+        lines.push(String::new());
+        lines.push(object.name.clone());
+        if !object.description.is_empty() {
+            lines.push(object.description.clone());
+        }
+        if let Some(fighter) = object.fighter {
+            let hp_fraction = fighter.hp as f32 / fighter.max_hp as f32;
+            let health = if hp_fraction >= 1.0 {
+                "unhurt"
+            } else if hp_fraction > 0.7 {
+                "lightly wounded"
+            } else if hp_fraction > 0.4 {
+                "wounded"
+            } else if hp_fraction > 0.15 {
+                "badly wounded"
             } else {
-                None
+                "almost dead"
+            };
+            lines.push(format!("It looks {}.", health));
+        }
+        if object.stuck > 0 {
+            lines.push("It is stuck in a trap.".into());
+        }
+        match object.ai {
+            Some(Ai::Basic) if can_see(object, player.x, player.y, map) => {
+                lines.push("It has noticed you.".into())
             }
-        } else {
-            None
+            Some(Ai::Basic) => lines.push("It hasn't noticed you.".into()),
+            Some(Ai::Confused { .. }) => lines.push("It is confused.".into()),
+            Some(Ai::Alerted { .. }) => lines.push("It is looking for something.".into()),
+            Some(Ai::Merchant) => lines.push("It has things to sell.".into()),
+            None => {}
+        }
+        match object.item {
+            Some(item) if discoveries.is_identified(item) => lines.push(item.description()),
+            Some(_) => lines.push("You don't know what it does yet.".into()),
+            None => {}
+        }
+        if let Some(Light {
+            lit,
+            fuel: Some(fuel),
+            ..
+        }) = object.light
+        {
+            let state = if lit { "lit" } else { "not lit" };
+            lines.push(format!(
+                "It is {}, with {} turns of fuel left.",
+                state, fuel
+            ));
+        }
+        if object.item == Some(Item::Corpse) {
+            lines.push(
+                match object.rot_chance() {
+                    chance if chance < 0.25 => "It looks fresh.",
+                    chance if chance < 0.75 => "It has started to smell.",
+                    _ => "It is rotten through.",
+                }
+                .into(),
+            );
         }
     }
+    lines.join("\n")
+}
 
-    pub fn render_bar(
-        self: &mut Tcod,
-        x: i32,
-        y: i32,
-        total_width: i32,
-        name: &str,
-        value: i32,
-        maximum: i32,
-        bar_color: Color,
-        back_color: Color,
-    ) {
-        // render a bar (HP, experience, etc).
-        let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
-
-        //render the background first
-        self.panel.set_default_background(back_color);
-        self.panel
-            .rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
-
-        // now render the bar on top
-        self.panel.set_default_background(bar_color);
-        if bar_width > 0 {
-            self.panel
-                .rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
+/// Update what the player knows of the level with what they can see now:
+/// explore the tiles in view, note the doors on them, and where things are.
+/// Monsters move around, so they are only known where they are seen, while
+/// anything else is remembered until the player sees it's gone.
+fn remember_surroundings(objects: &mut [Object], map: &mut Map, tcod: &Tcod) {
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if tcod.is_visible(x, y) {
+                let tile = &mut map[x as usize][y as usize];
+                tile.explored = true;
+                tile.remembered_door = tile.door;
+            }
         }
-
-        self.panel.set_default_foreground(colors::WHITE);
-        self.panel.print_ex(
-            x + total_width / 2,
-            y,
-            BackgroundFlag::None,
-            TextAlignment::Center,
-            &format!("{}: {}/{}", name, value, maximum),
-        )
     }
-    pub fn inventory_menu(self: &mut Tcod, inventory: &[Object], header: &str) -> Option<usize> {
-        let options = if inventory.is_empty() {
-            vec!["inventory is empty".into()]
-        } else {
-            inventory.iter().map(|item| item.name.clone()).collect()
-        };
 
-        let inventory_index = self.menu(header, &options, INVENTORY_WIDTH);
-
-        if !inventory.is_empty() {
-            inventory_index
-        } else {
-            None
+    for object in objects.iter_mut() {
+        let seen = tcod.is_visible(object.x, object.y)
+            || (object.always_visible && map[object.x as usize][object.y as usize].explored);
+        if seen && !object.is_hidden() {
+            object.last_seen = Some(object.pos());
+        } else if object.fighter.is_some() {
+            object.last_seen = None;
+        } else if let Some((x, y)) = object.last_seen {
+            if tcod.is_visible(x, y) {
+                object.last_seen = None;
+            }
         }
     }
 }
 
-fn use_item(
-    inventory_id: usize,
-    inventory: &mut Vec<Object>,
-    objects: &mut [Object],
-    messages: &mut Messages,
-    map: &mut Map,
-    tcod: &mut Tcod,
-) {
-    use Item::*;
-
-    if let Some(item) = inventory[inventory_id].item {
-        let on_use = match item {
-            Heal => cast_heal,
-            Lightning => cast_lightning,
-            Confuse => cast_confuse,
-            Fireball => cast_fireball,
-        };
+/// Show the whole explored level shrunk down to fit the screen, with the
+/// player, the stairs and everything else they remember, until a key is pressed.
+fn show_overview(tcod: &mut Tcod, map: &Map, objects: &[Object], dungeon_level: u32) {
+    let width = MAP_WIDTH / OVERVIEW_SCALE;
+    let height = MAP_HEIGHT / OVERVIEW_SCALE;
+    // one extra line at the top for the title
+    let mut window = Offscreen::new(width, height + 1);
+    window.set_default_foreground(colors::WHITE);
+    window.print_ex(
+        width / 2,
+        0,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        format!("Dungeon level {} - press any key", dungeon_level),
+    );
 
-        match on_use(inventory_id, objects, messages, map, tcod) {
-            UseResult::UsedUp => {
-                inventory.remove(inventory_id);
+    for x in 0..width {
+        for y in 0..height {
+            // show the floor if any explored tile in the square can be walked
+            // on, and a wall if only walls have been explored
+            let mut color = None;
+            for tile_x in (x * OVERVIEW_SCALE)..((x + 1) * OVERVIEW_SCALE) {
+                for tile_y in (y * OVERVIEW_SCALE)..((y + 1) * OVERVIEW_SCALE) {
+                    let tile = &map[tile_x as usize][tile_y as usize];
+                    if !tile.explored {
+                        continue;
+                    }
+                    let (dark_color, light_color) = tile.kind.colors();
+                    if !tile.blocked {
+                        color = Some(light_color);
+                    } else if color.is_none() {
+                        color = Some(dark_color);
+                    }
+                }
             }
-            UseResult::Cancelled => {
-                message(messages, "Cancelled", colors::WHITE);
+            if let Some(color) = color {
+                window.set_char_background(x, y + 1, color, BackgroundFlag::Set);
             }
         }
-    } else {
-        message(
-            messages,
-            format!("The {} cannot be used.", inventory[inventory_id].name),
-            colors::WHITE,
+    }
+
+    // draw the less important marks first, so the stairs and the player end
+    // up on top when they share a square with something else
+    let importance = |object: &Object| {
+        if object.feature == Some(Feature::Stairs) {
+            3
+        } else if object.fighter.is_some() {
+            2
+        } else if object.item.is_some() || object.trap.is_some() {
+            1
+        } else {
+            0
+        }
+    };
+    let mut marks: Vec<_> = objects
+        .iter()
+        .skip(1)
+        .filter(|o| o.last_seen.is_some() && importance(o) > 0)
+        .collect();
+    marks.sort_by_key(|o| importance(o));
+    for object in marks {
+        let (x, y) = object.last_seen.unwrap();
+        window.set_default_foreground(object.color);
+        window.put_char(
+            x / OVERVIEW_SCALE,
+            y / OVERVIEW_SCALE + 1,
+            object.char,
+            BackgroundFlag::None,
         );
     }
+    let player = &objects[PLAYER];
+    window.set_default_foreground(player.color);
+    window.put_char(
+        player.x / OVERVIEW_SCALE,
+        player.y / OVERVIEW_SCALE + 1,
+        player.char,
+        BackgroundFlag::None,
+    );
+
+    tcod.root.clear();
+    blit(
+        &window,
+        (0, 0),
+        (width, height + 1),
+        &mut tcod.root,
+        (
+            SCREEN_WIDTH / 2 - width / 2,
+            SCREEN_HEIGHT / 2 - (height + 1) / 2,
+        ),
+        1.0,
+        1.0,
+    );
+    tcod.root.flush();
+    tcod.root.wait_for_keypress(true);
 }
 
-fn handle_keys(
-    key: Key,
-    tcod: &mut Tcod,
-    map: &mut Map,
-    objects: &mut Vec<Object>,
-    inventory: &mut Vec<Object>,
-    messages: &mut Messages,
-) -> PlayerAction {
-    let player_alive = objects[PLAYER].alive;
-    match (key, player_alive) {
-        (Key { code: Up, .. }, true) => {
-            player_move_or_attack(0, -1, &map, messages, objects);
-            TookTurn
-        }
-        (Key { code: Down, .. }, true) => {
-            player_move_or_attack(0, 1, &map, messages, objects);
-            TookTurn
-        }
-        (Key { code: Left, .. }, true) => {
-            player_move_or_attack(-1, 0, &map, messages, objects);
-            TookTurn
-        }
-        (Key { code: Right, .. }, true) => {
-            player_move_or_attack(1, 0, &map, messages, objects);
-            TookTurn
+/// List the monsters and items in view next to the map, closest first.
+/// Corpses are left out, or a busy fight would push everything else off.
+fn render_sidebar(tcod: &mut Tcod, objects: &[Object]) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let mut listed: Vec<_> = objects
+        .iter()
+        .filter(|o| {
+            (o.item.is_some() && o.item != Some(Item::Corpse)) || (o.alive && o.ai.is_some())
+        })
+        .filter(|o| tcod.is_visible(o.x, o.y) && !o.is_hidden())
+        .collect();
+    listed.sort_by(|a, b| {
+        let distance_a = a.distance(player_x, player_y);
+        let distance_b = b.distance(player_x, player_y);
+        distance_a.partial_cmp(&distance_b).unwrap()
+    });
+
+    let sidebar = &mut tcod.sidebar;
+    sidebar.set_default_background(colors::BLACK);
+    sidebar.clear();
+    sidebar.set_default_foreground(colors::LIGHT_GREY);
+    sidebar.print_ex(1, 0, BackgroundFlag::None, TextAlignment::Left, "In view:");
+
+    let mut y = 2;
+    for object in listed {
+        // monsters take up two lines, for their HP bar
+        let lines = if object.fighter.is_some() { 2 } else { 1 };
+        if y + lines > CAMERA_HEIGHT {
+            break;
         }
-        (Key { printable: 'i', .. }, true) => {
-            let inventory_index = tcod.inventory_menu(
-                inventory,
-                "Press the key next to an item to use it, or any other to cancel.\n",
+        sidebar.set_default_foreground(object.color);
+        sidebar.put_char(1, y, object.char, BackgroundFlag::None);
+        sidebar.set_default_foreground(colors::WHITE);
+        sidebar.print_rect(3, y, SIDEBAR_WIDTH - 4, 1, &object.name);
+        if let Some(fighter) = object.fighter {
+            render_bar(
+                sidebar,
+                3,
+                y + 1,
+                SIDEBAR_WIDTH - 4,
+                "HP",
+                fighter.hp,
+                fighter.max_hp,
+                colors::LIGHT_RED,
+                colors::DARKER_RED,
             );
-            if let Some(inventory_index) = inventory_index {
-                use_item(inventory_index, inventory, objects, messages, map, tcod);
-                TookTurn
-            } else {
-                DidntTakeTurn
-            }
-        }
-        (Key { printable: 'g', .. }, true) => {
-            let item_id = objects
-                .iter()
-                .position(|object| object.pos() == objects[PLAYER].pos() && object.item.is_some());
-            if let Some(item_id) = item_id {
-                pick_item_up(item_id, objects, inventory, messages);
-            }
-            DidntTakeTurn
         }
-        (
-            Key {
-                code: Enter,
-                alt: true,
-                ..
-            },
-            _,
-        ) => {
-            let fullscreen = tcod.root.is_fullscreen();
-            tcod.root.set_fullscreen(!fullscreen);
-            DidntTakeTurn
-        }
-        (Key { code: Escape, .. }, _) => Exit,
-        _ => DidntTakeTurn,
+        y += lines;
+    }
+
+    blit(
+        &tcod.sidebar,
+        (0, 0),
+        (SIDEBAR_WIDTH, CAMERA_HEIGHT),
+        &mut tcod.root,
+        (SCREEN_WIDTH - SIDEBAR_WIDTH, 0),
+        1.0,
+        1.0,
+    );
+}
+
+fn render_bar(
+    panel: &mut Offscreen,
+    x: i32,
+    y: i32,
+    total_width: i32,
+    name: &str,
+    value: i32,
+    maximum: i32,
+    bar_color: Color,
+    back_color: Color,
+) {
+    // render a bar (HP, experience, etc).
+    let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
+
+    //render the background first
+    panel.set_default_background(back_color);
+    panel.rect(x, y, total_width, 1, false, BackgroundFlag::Screen);
+
+    // now render the bar on top
+    panel.set_default_background(bar_color);
+    if bar_width > 0 {
+        panel.rect(x, y, bar_width, 1, false, BackgroundFlag::Screen);
     }
+
+    panel.set_default_foreground(colors::WHITE);
+    panel.print_ex(
+        x + total_width / 2,
+        y,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        &format!("{}: {}/{}", name, value, maximum),
+    )
 }
 
 fn render_all(
     tcod: &mut Tcod,
     objects: &[Object],
-    map: &mut Map,
+    map: &Map,
     messages: &Messages,
     fov_recompute: bool,
 ) {
     if fov_recompute {
+        update_fov_map(map, &mut tcod.fov);
         let player = &objects[0];
-        tcod.fov
-            .compute_fov(player.x, player.y, TORCH_RADIUS, FOV_LIGHT_WALLS, FOV_ALGO)
+        let sight_radius = player.fighter.map_or(0, |f| f.sight_radius);
+        tcod.fov.compute_fov(
+            player.x,
+            player.y,
+            sight_radius,
+            FOV_LIGHT_WALLS,
+            tcod.config.fov_algorithm,
+        )
     }
+    // keep the player in the middle of the screen
+    let (player_x, player_y) = objects[PLAYER].pos();
+    tcod.move_camera(player_x, player_y);
+
     let mut to_draw: Vec<_> = objects
         .iter()
-        .filter(|o| tcod.fov.is_in_fov(o.x, o.y))
+        .filter(|o| {
+            let seen = tcod.is_visible(o.x, o.y)
+                || (o.always_visible && map[o.x as usize][o.y as usize].explored);
+            seen && !o.is_hidden()
+        })
         .collect();
 
     to_draw.sort_by(|o1, o2| o1.blocks.cmp(&o2.blocks));
 
-    for object in &to_draw {
-        object.draw(&mut tcod.con);
-    }
-
     // go through all tiles, and set their background color
     for y in 0..MAP_HEIGHT {
         for x in 0..MAP_WIDTH {
-            let visible = tcod.fov.is_in_fov(x, y);
-            let wall = map[x as usize][y as usize].block_sight;
-            let color = match (visible, wall) {
-                // outside of field of view:
-                (false, true) => COLOR_DARK_WALL,
-                (false, false) => COLOR_DARK_GROUND,
-                // inside fov:
-                (true, true) => COLOR_LIGHT_WALL,
-                (true, false) => COLOR_LIGHT_GROUND,
+            let visible = tcod.is_visible(x, y);
+            let door = map[x as usize][y as usize].door;
+            // secret doors look just like walls
+            let kind = if door == Some(Door::Secret) {
+                TileKind::Wall
+            } else {
+                map[x as usize][y as usize].kind
+            };
+            let (dark_color, light_color) = kind.colors();
+            let color = if visible {
+                // brighten the tile by how much light reaches it, tinted by its color
+                let light = tcod.lightmap[x as usize][y as usize];
+                let intensity = light_intensity(light);
+                colors::lerp(dark_color, light_color, intensity) * (light * (1.0 / intensity))
+            } else {
+                dark_color
             };
             // con.set_char_background(x, y, color, BackgroundFlag::Set);
 
-            let explored = &mut map[x as usize][y as usize].explored;
-            if visible {
-                // since it's visible, explore it
-                *explored = true;
-            }
-            if *explored {
-                // show explored tiles only (any visible tile is explored already)
+            let tile = &map[x as usize][y as usize];
+            if visible || tile.explored {
+                // show explored tiles only, and what's in view
                 tcod.con
                     .set_char_background(x, y, color, BackgroundFlag::Set);
+
+                // out of view, show the door that was here last time the
+                // player looked, dimmed
+                let glyph = if visible {
+                    door.and_then(Door::glyph).or_else(|| kind.glyph())
+                } else {
+                    tile.remembered_door
+                        .and_then(Door::glyph)
+                        .map(|(glyph, color)| (glyph, color * REMEMBERED_BRIGHTNESS))
+                        .or_else(|| kind.glyph())
+                };
+                match glyph {
+                    Some((glyph, glyph_color)) => {
+                        tcod.con.set_default_foreground(glyph_color);
+                        tcod.con.put_char(x, y, glyph, BackgroundFlag::None);
+                    }
+                    // wipe out anything remembered here before
+                    None => tcod.con.put_char(x, y, ' ', BackgroundFlag::None),
+                }
+            }
+        }
+    }
+
+    // out of view, show things where the player last saw them, dimmed;
+    // anything still drawn out of view, like the stairs, goes on top
+    for object in objects {
+        if let Some((x, y)) = object.last_seen {
+            if !tcod.is_visible(x, y) {
+                tcod.con
+                    .set_default_foreground(object.color * REMEMBERED_BRIGHTNESS);
+                tcod.con.put_char(x, y, object.char, BackgroundFlag::None);
+            }
+        }
+    }
+
+    // draw objects after the tiles, so they show up on top of doors
+    for object in &to_draw {
+        object.draw(&mut tcod.con);
+        // show how hurt monsters are by the color behind them
+        if let Some(fighter) = object.fighter {
+            if fighter.hp < fighter.max_hp && object.ai.is_some() {
+                tcod.con.set_char_background(
+                    object.x,
+                    object.y,
+                    health_color(fighter.hp, fighter.max_hp),
+                    BackgroundFlag::Set,
+                );
             }
         }
     }
 
+    // show only the part of the map the camera is looking at
     blit(
         &tcod.con,
-        (0, 0),
-        (MAP_WIDTH, MAP_HEIGHT),
+        tcod.camera,
+        (tcod.camera_width(), CAMERA_HEIGHT),
         &mut tcod.root,
         (0, 0),
         1.0,
         1.0,
     );
 
+    // damage numbers go on top of the map, and fade away after a while
+    let (camera_x, camera_y) = tcod.camera;
+    let camera_width = tcod.camera_width();
+    for floater in &mut tcod.floaters {
+        let (x, y) = (floater.x - camera_x, floater.y - camera_y);
+        if x >= 0 && y >= 0 && x < camera_width && y < CAMERA_HEIGHT {
+            tcod.root.set_default_foreground(floater.color);
+            tcod.root.print_ex(
+                x,
+                y,
+                BackgroundFlag::None,
+                TextAlignment::Center,
+                &floater.text,
+            );
+        }
+        floater.frames_left -= 1;
+    }
+    tcod.floaters.retain(|floater| floater.frames_left > 0);
+
+    if tcod.config.side_panel {
+        render_sidebar(tcod, objects);
+    }
+
     tcod.panel.set_default_background(colors::BLACK);
     tcod.panel.clear();
 
     // show the player's stats
     let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
     let max_hp = objects[PLAYER].fighter.map_or(0, |f| f.max_hp);
-    tcod.render_bar(
+    render_bar(
+        &mut tcod.panel,
         1,
         1,
         BAR_WIDTH,
@@ -1284,6 +5007,26 @@ fn render_all(
         colors::DARKER_RED,
     );
 
+    tcod.panel.set_default_foreground(colors::GOLD);
+    tcod.panel.print_ex(
+        1,
+        3,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Gold: {}", objects[PLAYER].gold),
+    );
+
+    if let Some(hunger) = objects[PLAYER].hunger() {
+        tcod.panel.set_default_foreground(hunger.color());
+        tcod.panel.print_ex(
+            1,
+            4,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            hunger.status(),
+        );
+    }
+
     tcod.panel.set_default_foreground(colors::LIGHT_GREY);
     tcod.panel.print_ex(
         1,
@@ -1317,3 +5060,77 @@ fn render_all(
         1.0,
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid_map() -> Map {
+        vec![vec![Tile::wall(); MAP_HEIGHT as usize]; MAP_WIDTH as usize]
+    }
+
+    #[test]
+    fn map_without_rooms_blocks_the_start() {
+        let map = solid_map();
+        let layout = Layout::from_rooms(vec![]);
+        assert_eq!(validate_map(&map, &layout), Err(MapProblem::StartBlocked));
+    }
+
+    #[test]
+    fn disconnected_rooms_get_tunnelled_together() {
+        let mut map = solid_map();
+        let first = Rect::new(1, 1, 5, 5);
+        let second = Rect::new(20, 20, 5, 5);
+        create_room(first, &mut map);
+        create_room(second, &mut map);
+        let layout = Layout::from_rooms(vec![first, second]);
+
+        assert_eq!(
+            validate_map(&map, &layout),
+            Err(MapProblem::RoomUnreachable(second))
+        );
+        assert_eq!(repair_map(&mut map, &layout), Ok(()));
+        assert_eq!(validate_map(&map, &layout), Ok(()));
+    }
+
+    #[test]
+    fn rooms_behind_secret_doors_are_unreachable() {
+        let mut map = solid_map();
+        let first = Rect::new(1, 1, 5, 5);
+        let second = Rect::new(10, 1, 5, 5);
+        create_room(first, &mut map);
+        create_room(second, &mut map);
+        create_h_tunnel(3, 12, 3, &mut map);
+        let layout = Layout::from_rooms(vec![first, second]);
+
+        set_door(10, 3, Door::Closed, &mut map);
+        assert_eq!(validate_map(&map, &layout), Ok(()));
+
+        set_door(10, 3, Door::Secret, &mut map);
+        assert_eq!(
+            validate_map(&map, &layout),
+            Err(MapProblem::RoomUnreachable(second))
+        );
+        assert_eq!(repair_map(&mut map, &layout), Ok(()));
+    }
+
+    #[test]
+    fn stairs_cut_off_from_the_start_get_tunnelled_to() {
+        let mut map = solid_map();
+        let room = Rect::new(1, 1, 5, 5);
+        create_room(room, &mut map);
+        // a single floor tile on its own, far from the room
+        map[40][40] = Tile::empty();
+        let layout = Layout {
+            rooms: vec![room],
+            start: room.center(),
+            stairs: (40, 40),
+        };
+        assert_eq!(
+            validate_map(&map, &layout),
+            Err(MapProblem::StairsUnreachable)
+        );
+        assert_eq!(repair_map(&mut map, &layout), Ok(()));
+        assert_eq!(validate_map(&map, &layout), Ok(()));
+    }
+}